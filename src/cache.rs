@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use crate::core::*;
+use crate::elf::*;
+
+// first line of the cache file, bump this if the format changes
+const CACHE_HEADER: &str = "[cache-v3]";
+
+// identifies a binary and the environment it was checked in
+// if any of this changed, the binary needs to be checked again
+#[derive(Debug, Clone, PartialEq)]
+struct CacheKey {
+    inode: u64,
+    size: u64,
+    mtime: u128,
+    lib_fingerprint: u64,
+}
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    key: CacheKey,
+    check: BinaryCheck,
+}
+
+// per binary results of previous runs, stored in $CARGO_HOME/rebuild-check/cache
+pub(crate) struct Cache {
+    path: PathBuf,
+    lib_fingerprint: u64,
+    // entries from the last run
+    old_entries: HashMap<String, CacheEntry>,
+    // entries we used or created in this run, these get saved
+    new_entries: Mutex<HashMap<String, CacheEntry>>,
+}
+
+impl Cache {
+    pub(crate) fn load(cargo_home: &Path, rustc_lib_path: &str) -> Self {
+        let mut path = cargo_home.to_path_buf();
+        path.push("rebuild-check");
+        path.push("cache");
+
+        // an unreadable or outdated cache is the same as having no cache
+        let old_entries = match fs::read_to_string(&path) {
+            Ok(content) => parse_cache_file(&content),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            path,
+            lib_fingerprint: library_search_path_fingerprint(rustc_lib_path),
+            old_entries,
+            new_entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // the problems of the binary, if we checked the very same binary before
+    pub(crate) fn lookup(&self, binary_path: &Path) -> Option<BinaryCheck> {
+        let key = self.key_for(binary_path)?;
+        let path = binary_path.display().to_string();
+        let entry = self.old_entries.get(&path)?;
+        if entry.key != key {
+            return None;
+        }
+        self.new_entries.lock().unwrap().insert(path, entry.clone());
+        Some(entry.check.clone())
+    }
+
+    pub(crate) fn insert(&self, binary_path: &Path, check: &BinaryCheck) {
+        if let Some(key) = self.key_for(binary_path) {
+            let entry = CacheEntry {
+                key,
+                check: check.clone(),
+            };
+            self.new_entries
+                .lock()
+                .unwrap()
+                .insert(binary_path.display().to_string(), entry);
+        }
+    }
+
+    pub(crate) fn save(&self) {
+        let entries = self.new_entries.lock().unwrap();
+        let mut content = String::from(CACHE_HEADER);
+        content.push('\n');
+        // sort to keep the file stable between runs
+        let mut paths = entries.keys().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            let entry = &entries[path];
+            let missing_versions = entry
+                .check
+                .missing_versions
+                .iter()
+                .map(|(library, version)| format!("{}:{}", library, version))
+                .collect::<Vec<_>>();
+            // "64:62:/lib64/ld-linux-x86-64.so.2", empty if this is not an ELF file
+            let elf = match entry.check.elf {
+                Some(ref elf) => format!(
+                    "{}:{}:{}",
+                    if elf.is_64bit { 64 } else { 32 },
+                    elf.machine,
+                    elf.interpreter.as_ref().map_or("", String::as_str)
+                ),
+                None => String::new(),
+            };
+            content.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                path,
+                entry.key.inode,
+                entry.key.size,
+                entry.key.mtime,
+                entry.key.lib_fingerprint,
+                entry.check.missing_libs.join(" "),
+                missing_versions.join(" "),
+                elf
+            ));
+        }
+
+        // failing to write the cache is not fatal, we just check everything again next time
+        if let Some(dir) = self.path.parent() {
+            if fs::create_dir_all(dir).is_err() {
+                return;
+            }
+        }
+        if let Ok(mut file) = File::create(&self.path) {
+            let _ = file.write_all(content.as_bytes());
+        }
+    }
+
+    fn key_for(&self, binary_path: &Path) -> Option<CacheKey> {
+        let metadata = fs::metadata(binary_path).ok()?;
+        let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(CacheKey {
+            inode: metadata.ino(),
+            size: metadata.len(),
+            mtime: mtime.as_nanos(),
+            lib_fingerprint: self.lib_fingerprint,
+        })
+    }
+}
+
+fn parse_cache_file(content: &str) -> HashMap<String, CacheEntry> {
+    let mut entries = HashMap::new();
+    let mut lines = content.lines();
+    if lines.next() != Some(CACHE_HEADER) {
+        return entries;
+    }
+
+    for line in lines {
+        // path inode size mtime fingerprint missing_libs missing_versions elf
+        let fields = line.split('\t').collect::<Vec<_>>();
+        if fields.len() != 8 {
+            continue;
+        }
+        let key = match (
+            fields[1].parse(),
+            fields[2].parse(),
+            fields[3].parse(),
+            fields[4].parse(),
+        ) {
+            (Ok(inode), Ok(size), Ok(mtime), Ok(lib_fingerprint)) => CacheKey {
+                inode,
+                size,
+                mtime,
+                lib_fingerprint,
+            },
+            _ => continue,
+        };
+        let missing_libs = fields[5]
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        // "libc.so.6:GLIBC_2.28"
+        let missing_versions = fields[6]
+            .split_whitespace()
+            .filter_map(|missing| {
+                let mut parts = missing.splitn(2, ':');
+                Some((parts.next()?.to_string(), parts.next()?.to_string()))
+            })
+            .collect::<Vec<_>>();
+        let check = BinaryCheck {
+            missing_libs,
+            missing_versions,
+            // symbols are only checked with --deep, which does not use the cache
+            missing_symbols: Vec::new(),
+            elf: parse_elf_info(fields[7]),
+        };
+        entries.insert(fields[0].to_string(), CacheEntry { key, check });
+    }
+    entries
+}
+
+fn parse_elf_info(field: &str) -> Option<ElfInfo> {
+    let mut parts = field.splitn(3, ':');
+    let is_64bit = parts.next()? == "64";
+    let machine = parts.next()?.parse().ok()?;
+    let interpreter = match parts.next()? {
+        "" => None,
+        interpreter => Some(interpreter.to_string()),
+    };
+    Some(ElfInfo {
+        is_64bit,
+        machine,
+        interpreter,
+    })
+}
+
+fn mtime_of(path: &Path) -> Option<u128> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_nanos())
+}
+
+// changes whenever the libraries a binary may be linked against change:
+// the rustc sysroot gets updated or the dynamic linker cache is regenerated
+fn library_search_path_fingerprint(rustc_lib_path: &str) -> u64 {
+    // the fingerprint is stored in the cache file, so it has to be the same for every build of
+    // this tool, which DefaultHasher doesn't promise
+    let mut hasher = Fnv1a::new();
+    hasher.write_field(rustc_lib_path.as_bytes());
+    let ld_library_path = std::env::var_os("LD_LIBRARY_PATH").unwrap_or_default();
+    hasher.write_field(ld_library_path.as_bytes());
+    hasher.write_mtime(mtime_of(Path::new("/etc/ld.so.cache")));
+
+    // the hashed library names in the sysroot change with every toolchain update
+    let mut sysroot_libs = match fs::read_dir(rustc_lib_path) {
+        Ok(dir) => dir
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    sysroot_libs.sort();
+    for lib in &sysroot_libs {
+        hasher.write_field(lib.as_bytes());
+    }
+    hasher.write_mtime(mtime_of(Path::new(rustc_lib_path)));
+
+    hasher.finish()
+}
+
+// 64-bit FNV-1a
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // NUL can't appear in paths, so it keeps ("ab", "c") and ("a", "bc") apart
+    fn write_field(&mut self, bytes: &[u8]) {
+        self.write(bytes);
+        self.write(&[0]);
+    }
+
+    fn write_mtime(&mut self, mtime: Option<u128>) {
+        match mtime {
+            Some(mtime) => self.write_field(mtime.to_string().as_bytes()),
+            None => self.write_field(b"-"),
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn fingerprint_hash_is_fixed() {
+        let mut hasher = Fnv1a::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn cache_roundtrip() {
+        let cargo_home = FakeCargoHome::new("cache_roundtrip")
+            .working_binary("fine")
+            .build();
+        let binary = cargo_home.bin_dir().join("fine");
+        let missing = BinaryCheck {
+            missing_libs: vec![String::from("libfoo-0123456789abcdef.so")],
+            missing_versions: vec![(String::from("libc.so.6"), String::from("GLIBC_2.28"))],
+            missing_symbols: Vec::new(),
+            elf: Some(ElfInfo {
+                is_64bit: true,
+                machine: 62,
+                interpreter: Some(String::from("/lib64/ld-linux-x86-64.so.2")),
+            }),
+        };
+
+        let cache = Cache::load(cargo_home.path(), "/nonexistent");
+        assert_eq!(cache.lookup(&binary), None);
+        cache.insert(&binary, &missing);
+        cache.save();
+
+        let cache = Cache::load(cargo_home.path(), "/nonexistent");
+        assert_eq!(cache.lookup(&binary), Some(missing));
+    }
+
+    #[test]
+    fn cache_invalidated_by_changed_binary() {
+        let cargo_home = FakeCargoHome::new("cache_changed_binary")
+            .working_binary("fine")
+            .build();
+        let binary = cargo_home.bin_dir().join("fine");
+
+        let cache = Cache::load(cargo_home.path(), "/nonexistent");
+        cache.insert(&binary, &BinaryCheck::default());
+        cache.save();
+
+        // replace the binary with a different one
+        fs::remove_file(&binary).unwrap();
+        let cargo_home = cargo_home.broken_binary("fine", "libfoo-0123456789abcdef.so");
+        let cache = Cache::load(cargo_home.path(), "/nonexistent");
+        assert_eq!(cache.lookup(&binary), None);
+    }
+
+    #[test]
+    fn cache_invalidated_by_changed_sysroot() {
+        let cargo_home = FakeCargoHome::new("cache_changed_sysroot")
+            .working_binary("fine")
+            .build();
+        let binary = cargo_home.bin_dir().join("fine");
+
+        let cache = Cache::load(cargo_home.path(), "/nonexistent");
+        cache.insert(&binary, &BinaryCheck::default());
+        cache.save();
+
+        let cache = Cache::load(cargo_home.path(), "/some/other/sysroot/lib");
+        assert_eq!(cache.lookup(&binary), None);
+    }
+
+    #[test]
+    fn cache_written_unless_disabled() {
+        let cargo_home = FakeCargoHome::new("cache_written")
+            .broken_crate("broken", "0.2.0", "libfoo-0123456789abcdef.so")
+            .build();
+        let cache_file = cargo_home.path().join("rebuild-check").join("cache");
+
+        cargo_home.run(&["--no-cache"]);
+        assert!(!cache_file.exists());
+
+        cargo_home.run(&[]);
+        let content = fs::read_to_string(&cache_file).unwrap();
+        assert!(content.starts_with("[cache-v3]\n"));
+        assert!(content.contains("\tlibfoo-0123456789abcdef.so\t\t"));
+
+        // the cached result is reported the same way
+        let output = cargo_home.run(&["--full"]);
+        let stderr = &output.stderr;
+        assert_eq!(
+            stderr,
+            "    Binary 'broken' is missing:
+\t\tlibfoo-0123456789abcdef.so (rust library)
+    To fix: rebuild the crate\n"
+        );
+    }
+
+    #[test]
+    fn cache_ignores_unknown_format() {
+        let entries = parse_cache_file("[cache-v2]\n/bin/foo\t1\t2\t3\t4\t\n");
+        assert!(entries.is_empty());
+    }
+}
@@ -1,14 +1,131 @@
 use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::string::String;
 
-fn has_binary(binary: &str) -> bool {
-    // check if we can find the binary
-    Command::new(&binary)
+pub(crate) fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+// where the shell would find a program, paths like "/usr/bin/rustc" are taken as they are
+pub(crate) fn find_in_path(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        let path = PathBuf::from(program);
+        return if is_executable(&path) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| is_executable(candidate))
+}
+
+pub(crate) fn has_binary(binary: &str) -> bool {
+    // running it could take a while or print things, looking for it is enough
+    find_in_path(binary).is_some()
+}
+
+// version of cargo or rustc, pre-release tags like "-nightly" are dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Version {
+    major: u32,
+    minor: u32,
+    patch: u32,
+}
+
+impl Version {
+    const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    // "cargo 1.28.0-nightly (b4dbd5d1b 2018-06-29)" or "rustc 1.27.0 (3eda71b00 2018-06-19)"
+    pub(crate) fn parse(version_output: &str) -> Option<Self> {
+        let (name, version) = {
+            let mut words = version_output.split_whitespace();
+            (words.next()?, words.next()?)
+        };
+        let mut numbers = version
+            .split('-')
+            .next()?
+            .split('.')
+            .map(|number| number.parse::<u32>());
+        let version = Self::new(
+            numbers.next()?.ok()?,
+            numbers.next()?.ok()?,
+            numbers.next()?.ok()?,
+        );
+        // cargo was 0.27 when rust was 1.26, and took the version of rust after that
+        if name == "cargo" && version.major == 0 && version.minor > 0 {
+            Some(Self::new(1, version.minor - 1, version.patch))
+        } else {
+            Some(version)
+        }
+    }
+
+    // the patch level does not matter, cargo and rustc of a toolchain can differ in it
+    pub(crate) fn same_release(self, other: Self) -> bool {
+        (self.major, self.minor) == (other.major, other.minor)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+// flags a rebuild may pass to "cargo install", and the first cargo that knows them
+const FLAG_MINIMUMS: &[(&str, Version)] = &[
+    ("--locked", Version::new(1, 12, 0)),
+    ("--registry", Version::new(1, 34, 0)),
+    ("--offline", Version::new(1, 36, 0)),
+];
+
+fn program_version(program: &str) -> Option<Version> {
+    let output = Command::new(program)
+        .arg("--version")
         .env("LANG", "en_US")
         .env("LC_ALL", "en_US")
         .output()
-        .is_ok()
+        .ok()?;
+    Version::parse(&String::from_utf8_lossy(&output.stdout))
+}
+
+pub(crate) fn cargo_version() -> Option<Version> {
+    program_version("cargo")
+}
+
+pub(crate) fn rustc_version() -> Option<Version> {
+    program_version(&get_rustc())
+}
+
+// Err with the reason if this cargo is too old for the flag, an unknown version gets the
+// benefit of the doubt
+pub(crate) fn check_cargo_flag(flag: &str, cargo: Option<Version>) -> Result<(), String> {
+    let minimum = FLAG_MINIMUMS
+        .iter()
+        .find(|(known, _)| *known == flag)
+        .map(|(_, minimum)| *minimum);
+    match (minimum, cargo) {
+        (Some(minimum), Some(cargo)) if cargo < minimum => Err(format!(
+            "cargo {} does not support {}, it needs cargo {} or newer",
+            cargo, flag, minimum
+        )),
+        _ => Ok(()),
+    }
 }
 
 pub(crate) fn get_rustc() -> String {
@@ -45,8 +162,56 @@ pub(crate) fn all_binaries_available() -> Result<bool, String> {
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use crate::test_helpers::*;
     use std::process::Command;
+
+    #[test]
+    fn programs_in_path() {
+        assert!(find_in_path("sh").is_some());
+        assert!(find_in_path("/bin/sh").is_some());
+        assert_eq!(find_in_path("rebuild-check-no-such-program"), None);
+        // only executable files count
+        assert_eq!(find_in_path("/etc/passwd"), None);
+        assert_eq!(find_in_path("/tmp"), None);
+    }
+
+    #[test]
+    fn versions_and_flag_minimums() {
+        let version = |output: &str| Version::parse(output).map(|version| version.to_string());
+        assert_eq!(
+            version("cargo 1.28.0-nightly (b4dbd5d1b 2018-06-29)"),
+            Some(String::from("1.28.0"))
+        );
+        assert_eq!(
+            version("rustc 1.27.0 (3eda71b00 2018-06-19)\n"),
+            Some(String::from("1.27.0"))
+        );
+        // before cargo took the version of rust
+        assert_eq!(
+            version("cargo 0.26.0 (0e7c5a931 2018-04-06)"),
+            Some(String::from("1.25.0"))
+        );
+        assert_eq!(version("rustc"), None);
+        assert_eq!(version("error: no such toolchain"), None);
+
+        let cargo = Version::parse("cargo 1.34.2 (ab82e070 2019-05-14)");
+        assert_eq!(check_cargo_flag("--locked", cargo), Ok(()));
+        assert_eq!(check_cargo_flag("--registry", cargo), Ok(()));
+        assert_eq!(
+            check_cargo_flag("--offline", cargo),
+            Err(String::from(
+                "cargo 1.34.2 does not support --offline, it needs cargo 1.36.0 or newer"
+            ))
+        );
+        assert_eq!(check_cargo_flag("--offline", None), Ok(()));
+        assert_eq!(check_cargo_flag("--force", cargo), Ok(()));
+
+        let rustc = Version::parse("rustc 1.34.0 (91856ed52 2019-04-10)").unwrap();
+        assert!(cargo.unwrap().same_release(rustc));
+        assert!(!cargo.unwrap().same_release(Version::new(1, 35, 0)));
+    }
+
     #[test]
     fn no_binary_found() {
         let mut dir = run_cargo_build();
@@ -11,6 +11,201 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
         .long("rebuild-all")
         .help("Rebuild all installed crates unconditionally");
 
+    let skip_static = Arg::with_name("skip-static")
+        .long("skip-static")
+        .requires("rebuild-all")
+        .help("Don't rebuild crates whose binaries are all statically linked");
+
+    let fix = Arg::with_name("fix")
+        .long("fix")
+        .value_name("STRATEGY")
+        .takes_value(true)
+        .possible_values(&["rebuild", "relink", "wrapper"])
+        .help(
+            "Rebuild broken crates, or relink or wrap them to use an installed toolchain that \
+             has the libraries they miss [default: rebuild]",
+        );
+
+    let git_policy = Arg::with_name("git-policy")
+        .long("git-policy")
+        .value_name("POLICY")
+        .takes_value(true)
+        .possible_values(&["exact", "follow"])
+        .help(
+            "Rebuild git crates at the commit they were installed from, or follow the branch or \
+             tag they were installed with; an exact rebuild records the commit instead of the \
+             branch or tag [default: exact]",
+        );
+
+    // passed on to "cargo install"
+    let locked = Arg::with_name("locked").long("locked").help(
+        "Rebuild with the dependency versions of the Cargo.lock the crates were published with",
+    );
+
+    let offline = Arg::with_name("offline")
+        .long("offline")
+        .help("Rebuild without accessing the network");
+
+    let no_cache = Arg::with_name("no-cache")
+        .long("no-cache")
+        .help("Check every binary again instead of reusing results of previous runs");
+
+    let root = Arg::with_name("root")
+        .long("root")
+        .value_name("DIR")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .help("Check crates installed into DIR instead of the configured install roots");
+
+    let deep = Arg::with_name("deep")
+        .long("deep")
+        .help("Also check that the libraries provide every symbol the binaries need (slow)");
+
+    let smoke = Arg::with_name("smoke")
+        .long("smoke")
+        .help("Also run every binary with --version to find the ones that fail at runtime");
+
+    let verbose = Arg::with_name("verbose")
+        .short("v")
+        .long("verbose")
+        .help("Show architecture and interpreter of every binary");
+
+    let diff = Arg::with_name("diff")
+        .long("diff")
+        .conflicts_with("full")
+        .help("Only report changes since the previous check");
+
+    let full = Arg::with_name("full")
+        .long("full")
+        .help("Report every checked crate (default)");
+
+    let sort = Arg::with_name("sort")
+        .long("sort")
+        .value_name("ORDER")
+        .takes_value(true)
+        .possible_values(&["name", "status", "size"])
+        .default_value("name")
+        .help("Order of the listed crates");
+
+    let simulate_remove = Arg::with_name("simulate-remove-toolchain")
+        .long("simulate-remove-toolchain")
+        .value_name("NAME")
+        .takes_value(true)
+        .conflicts_with("simulate-default")
+        .help("Show which crates would break if toolchain NAME was removed");
+
+    let simulate_default = Arg::with_name("simulate-default")
+        .long("simulate-default")
+        .value_name("NAME")
+        .takes_value(true)
+        .help("Show which crates would break if toolchain NAME was the default");
+
+    let watch = SubCommand::with_name("watch")
+        .about("Check again whenever the toolchain or the installed crates change")
+        .arg(
+            Arg::with_name("auto-rebuild")
+                .short("a")
+                .long("auto")
+                .help("Try to automatically reinstall crates that broke"),
+        )
+        .arg(&git_policy)
+        .arg(&locked)
+        .arg(&offline)
+        .arg(&no_cache)
+        .arg(&root)
+        .arg(&deep)
+        .arg(&smoke);
+
+    let history = SubCommand::with_name("history")
+        .about("Show previous checks and rebuilds")
+        .arg(
+            Arg::with_name("crate")
+                .value_name("CRATE")
+                .help("Show when CRATE broke and what fixed it"),
+        )
+        .arg(
+            Arg::with_name("failures")
+                .long("failures")
+                .conflicts_with("crate")
+                .help("Show crates that failed to rebuild repeatedly"),
+        );
+
+    let check = SubCommand::with_name("check")
+        .about("Check the installed crates and record the result, fails if any is broken")
+        .arg(&no_cache)
+        .arg(&root)
+        .arg(&deep)
+        .arg(&smoke)
+        .arg(&verbose)
+        .arg(&diff)
+        .arg(&full)
+        .arg(&sort)
+        .arg(&simulate_remove)
+        .arg(&simulate_default);
+
+    let rebuild = SubCommand::with_name("rebuild")
+        .about("Reinstall broken crates, or the given ones")
+        .arg(
+            Arg::with_name("crates")
+                .value_name("CRATE")
+                .multiple(true)
+                .conflicts_with("rebuild-all")
+                .help("Reinstall these crates whether they are broken or not"),
+        )
+        .arg(
+            Arg::with_name("rebuild-all")
+                .long("all")
+                .help("Reinstall all installed crates"),
+        )
+        .arg(fix.clone().conflicts_with_all(&["crates", "rebuild-all"]))
+        .arg(&git_policy)
+        .arg(&locked)
+        .arg(&offline)
+        .arg(&skip_static)
+        .arg(&no_cache)
+        .arg(&root)
+        .arg(&deep)
+        .arg(&smoke)
+        .arg(&diff)
+        .arg(&full)
+        .arg(&sort);
+
+    let list = SubCommand::with_name("list")
+        .about("List the installed crates with their source, version and binaries")
+        .arg(&root);
+
+    let explain = SubCommand::with_name("explain")
+        .about("Explain in detail how a crate or binary links and why it is broken")
+        .arg(
+            Arg::with_name("target")
+                .value_name("CRATE|BINARY")
+                .required(true)
+                .help("Installed crate or binary to explain"),
+        )
+        .arg(&root);
+
+    let who_needs = SubCommand::with_name("who-needs")
+        .about(
+            "List the installed binaries that load a library, directly or through other libraries",
+        )
+        .arg(
+            Arg::with_name("library")
+                .value_name("SONAME|PATH")
+                .required(true)
+                .help("Library soname, library path or a directory of libraries like a toolchain"),
+        )
+        .arg(&root);
+
+    let toolchains = SubCommand::with_name("toolchains")
+        .about("Show which installed binaries need which rustup toolchain")
+        .arg(&root);
+
+    let doctor = SubCommand::with_name("doctor")
+        .about("Check the environment: cargo, rustc, rustup, PATH and the dynamic loader config");
+
+    // the flags without a subcommand stay as aliases: no flags is "check", --auto is
+    // "rebuild" and --rebuild-all is "rebuild --all"
     App::new("cargo-rebuild-check")
         .version(crate_version!())
         .bin_name("cargo")
@@ -24,10 +219,70 @@ pub(crate) fn gen_clap<'a>() -> ArgMatches<'a> {
                 .author("matthiaskrgr")
                 .arg(&auto_rebuild)
                 .arg(&rebuild_all)
+                .arg(
+                    fix.clone()
+                        .requires("auto-rebuild")
+                        .conflicts_with("rebuild-all"),
+                )
+                .arg(&git_policy)
+                .arg(&locked)
+                .arg(&offline)
+                .arg(&skip_static)
+                .arg(&no_cache)
+                .arg(&root)
+                .arg(&deep)
+                .arg(&smoke)
+                .arg(&verbose)
+                .arg(&diff)
+                .arg(&full)
+                .arg(&sort)
+                .arg(
+                    simulate_remove
+                        .clone()
+                        .conflicts_with_all(&["auto-rebuild", "rebuild-all"]),
+                )
+                .arg(
+                    simulate_default
+                        .clone()
+                        .conflicts_with_all(&["auto-rebuild", "rebuild-all"]),
+                )
+                .subcommand(check.clone())
+                .subcommand(rebuild.clone())
+                .subcommand(list.clone())
+                .subcommand(explain.clone())
+                .subcommand(who_needs.clone())
+                .subcommand(toolchains.clone())
+                .subcommand(doctor.clone())
+                .subcommand(watch.clone())
+                .subcommand(history.clone())
                 .setting(AppSettings::Hidden) // hide subcommand from --help
         ) // subcommand
+        .subcommand(check)
+        .subcommand(rebuild)
+        .subcommand(list)
+        .subcommand(explain)
+        .subcommand(who_needs)
+        .subcommand(toolchains)
+        .subcommand(doctor)
+        .subcommand(watch)
+        .subcommand(history)
         .arg(&auto_rebuild)
         .arg(&rebuild_all)
+        .arg(fix.requires("auto-rebuild").conflicts_with("rebuild-all"))
+        .arg(&git_policy)
+        .arg(&locked)
+        .arg(&offline)
+        .arg(&skip_static)
+        .arg(&no_cache)
+        .arg(&root)
+        .arg(&deep)
+        .arg(&smoke)
+        .arg(&verbose)
+        .arg(&diff)
+        .arg(&full)
+        .arg(&sort)
+        .arg(simulate_remove.conflicts_with_all(&["auto-rebuild", "rebuild-all"]))
+        .arg(simulate_default.conflicts_with_all(&["auto-rebuild", "rebuild-all"]))
         .get_matches()
 }
 
@@ -56,13 +311,259 @@ mod tests {
 matthiaskrgr
 Find installed crates that need rebuild due to broken library links\n
 USAGE:
-    cargo [FLAGS]\n
+    cargo [FLAGS] [OPTIONS] [SUBCOMMAND]\n
 FLAGS:
     -a, --auto           Try to automatically reinstall broken crates
+        --deep           Also check that the libraries provide every symbol the binaries need (slow)
+        --diff           Only report changes since the previous check
+        --full           Report every checked crate (default)
     -h, --help           Prints help information
+        --locked         Rebuild with the dependency versions of the Cargo.lock the crates were published with
+        --no-cache       Check every binary again instead of reusing results of previous runs
+        --offline        Rebuild without accessing the network
     -r, --rebuild-all    Rebuild all installed crates unconditionally
-    -V, --version        Prints version information\n";
+        --skip-static    Don't rebuild crates whose binaries are all statically linked
+        --smoke          Also run every binary with --version to find the ones that fail at runtime
+    -V, --version        Prints version information
+    -v, --verbose        Show architecture and interpreter of every binary\n
+OPTIONS:
+        --fix <STRATEGY>                      Rebuild broken crates, or relink or wrap them to use an installed
+                                              toolchain that has the libraries they miss [default: rebuild] [possible
+                                              values: rebuild, relink, wrapper]
+        --git-policy <POLICY>                 Rebuild git crates at the commit they were installed from, or follow the
+                                              branch or tag they were installed with; an exact rebuild records the
+                                              commit instead of the branch or tag [default: exact] [possible values:
+                                              exact, follow]
+        --root <DIR>...                       Check crates installed into DIR instead of the configured install roots
+        --simulate-default <NAME>             Show which crates would break if toolchain NAME was the default
+        --simulate-remove-toolchain <NAME>    Show which crates would break if toolchain NAME was removed
+        --sort <ORDER>                        Order of the listed crates [default: name]  [possible values: name,
+                                              status, size]\n
+SUBCOMMANDS:
+    check         Check the installed crates and record the result, fails if any is broken
+    doctor        Check the environment: cargo, rustc, rustup, PATH and the dynamic loader config
+    explain       Explain in detail how a crate or binary links and why it is broken
+    help          Prints this message or the help of the given subcommand(s)
+    history       Show previous checks and rebuilds
+    list          List the installed crates with their source, version and binaries
+    rebuild       Reinstall broken crates, or the given ones
+    toolchains    Show which installed binaries need which rustup toolchain
+    watch         Check again whenever the toolchain or the installed crates change
+    who-needs     List the installed binaries that load a library, directly or through other libraries\n";
         assert_eq!(output, help_text);
     }
 
+    #[test]
+    fn report_all_crates_healthy() {
+        let cargo_home = FakeCargoHome::new("healthy")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        let stdout = &output.stdout;
+        assert_eq!(
+            stdout,
+            "  Checking crate fine 1.0.0\n\n  Everything looks good! :)\n"
+        );
+    }
+
+    #[test]
+    fn report_broken_link() {
+        let cargo_home = FakeCargoHome::new("broken_link")
+            .registry_crate("fine", "1.0.0", &["fine"])
+            .registry_crate("broken", "0.2.0", &["broken"])
+            .working_binary("fine")
+            .broken_binary("broken", "libfoo-0123456789abcdef.so")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        let stdout = &output.stdout;
+        let stderr = &output.stderr;
+        assert_eq!(
+            stdout,
+            "  Checking crate broken 0.2.0
+  Checking crate fine 1.0.0\n
+  Crates needing rebuild: broken\n"
+        );
+        assert_eq!(
+            stderr,
+            "    Binary 'broken' is missing:
+\t\tlibfoo-0123456789abcdef.so (rust library)
+    To fix: rebuild the crate\n"
+        );
+    }
+
+    #[test]
+    fn report_missing_binary() {
+        let cargo_home = FakeCargoHome::new("missing_binary")
+            .registry_crate("twobins", "0.3.0", &["present", "absent"])
+            .working_binary("present")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        let stdout = &output.stdout;
+        let stderr = &output.stderr;
+        assert_eq!(
+            stdout,
+            "  Checking crate twobins 0.3.0\n\n  Crates needing rebuild: twobins\n"
+        );
+        assert_eq!(
+            *stderr,
+            format!(
+                "    Binary 'absent' not found in '{}'\n",
+                cargo_home.bin_dir().display()
+            )
+        );
+    }
+
+    #[test]
+    fn report_broken_binary_followed_by_healthy_one() {
+        // a later healthy binary must not mask an earlier broken one
+        let cargo_home = FakeCargoHome::new("broken_then_healthy")
+            .registry_crate("mixed", "0.1.0", &["broken", "fine"])
+            .broken_binary("broken", "libbar-fedcba9876543210.so")
+            .working_binary("fine")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        let stdout = &output.stdout;
+        assert!(stdout.ends_with("\n  Crates needing rebuild: mixed\n"));
+    }
+
+    #[test]
+    fn check_fails_on_broken_crates() {
+        let cargo_home = FakeCargoHome::new("check_subcommand")
+            .working_crate("fine", "1.0.0")
+            .build();
+        let output = cargo_home.run(&["check", "--full"]);
+        assert!(output.status.success());
+
+        let cargo_home = FakeCargoHome::new("check_subcommand_broken")
+            .broken_crate("broken", "1.0.0", "librebuild-check-test.so.1")
+            .build();
+        let output = cargo_home.run(&["check", "--full"]);
+        assert_eq!(output.status.code(), Some(5));
+        // without a subcommand, broken crates are only reported
+        let output = cargo_home.run(&["--full"]);
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn rebuild_unknown_crate() {
+        let cargo_home = FakeCargoHome::new("rebuild_unknown")
+            .crates_toml_line("\"other 0.3.0 (registry+https://example.com/index)\" = [\"other\"]")
+            .broken_binary("other", "libfoo-0123456789abcdef.so")
+            .build();
+        // nothing gets rebuilt if one of the crates is not installed
+        let output = cargo_home.run(&["rebuild", "other", "typo", "--full"]);
+        assert_eq!(output.status.code(), Some(3));
+        assert!(output.stdout.is_empty());
+        assert_eq!(output.stderr, "Not installed: typo\n");
+    }
+
+    #[test]
+    fn list_crates() {
+        let cargo_home = FakeCargoHome::new("list")
+            .registry_crate("ripgrep", "0.8.0", &["rg"])
+            .crates_toml_line(
+                "\"racer 2.0.12 (path+file:///tmp/racer)\" = [\"racer\", \"racer-daemon\"]",
+            )
+            .build();
+        let output = cargo_home.run(&["list"]);
+        assert!(output.stderr.is_empty());
+        assert_eq!(
+            output.stdout,
+            "  racer 2.0.12
+    Source: path /tmp/racer
+    Binaries: racer racer-daemon
+  ripgrep 0.8.0
+    Source: crates.io
+    Binaries: rg\n"
+        );
+    }
+
+    #[test]
+    fn report_sorted() {
+        let cargo_home = FakeCargoHome::new("sorted")
+            .registry_crate("zeta", "0.1.0", &["zeta"])
+            .registry_crate("alpha", "1.0.0", &["alpha"])
+            .registry_crate("mid", "0.5.0", &["mid1", "mid2"])
+            .broken_binary("zeta", "libfoo-0123456789abcdef.so")
+            .working_binary("alpha")
+            .working_binary("mid1")
+            .working_binary("mid2")
+            .build();
+        let listing = |sort_order: &str| {
+            let output = cargo_home.run(&["--full", "--sort", sort_order]);
+            output
+                .stdout
+                .lines()
+                .filter(|line| line.starts_with("  Checking crate "))
+                .map(|line| line.replace("  Checking crate ", ""))
+                .collect::<Vec<_>>()
+        };
+
+        // the same order every time, no matter which crate was checked first
+        for _ in 0..3 {
+            assert_eq!(
+                listing("name"),
+                vec!["alpha 1.0.0", "mid 0.5.0", "zeta 0.1.0"]
+            );
+        }
+        assert_eq!(
+            listing("status"),
+            vec!["zeta 0.1.0", "alpha 1.0.0", "mid 0.5.0"]
+        );
+        // two binaries outweigh one
+        assert_eq!(listing("size")[0], "mid 0.5.0");
+    }
+
+    #[test]
+    fn report_explicit_install_root() {
+        let cargo_home = FakeCargoHome::new("home_with_broken")
+            .broken_crate("broken", "0.2.0", "libfoo-0123456789abcdef.so")
+            .build();
+        let install_root = FakeCargoHome::new("explicit_root")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        // only the given root is checked, $CARGO_HOME is ignored
+        let root = install_root.path().display().to_string();
+        let output = cargo_home.run(&["--full", "--root", &root]);
+        assert!(output.status.success());
+        assert!(output.stderr.is_empty());
+        let stdout = &output.stdout;
+        assert_eq!(
+            stdout,
+            "  Checking crate fine 1.0.0\n\n  Everything looks good! :)\n"
+        );
+    }
+
+    #[test]
+    fn report_configured_install_root_and_cargo_home() {
+        let cargo_home = FakeCargoHome::new("home_with_fine")
+            .working_crate("fine", "1.0.0")
+            .build();
+        let install_root = FakeCargoHome::new("configured_root")
+            .broken_crate("broken", "0.2.0", "libfoo-0123456789abcdef.so")
+            .build();
+
+        let root = install_root.path().display().to_string();
+        let output = cargo_home.run_with_env(&["--full"], &[("CARGO_INSTALL_ROOT", &root)]);
+        let stdout = &output.stdout;
+        let expected = format!(
+            "  Install root '{}'
+  Checking crate broken 0.2.0\n
+  Crates needing rebuild: broken
+  Install root '{}'
+  Checking crate fine 1.0.0\n
+  Everything looks good! :)\n",
+            root,
+            cargo_home.path().display()
+        );
+        assert_eq!(*stdout, expected);
+    }
+
 } // mod test
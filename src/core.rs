@@ -1,12 +1,29 @@
 #[cfg(test)]
 use test::*;
 
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::ptr;
+use std::time::{Duration, Instant};
 
 use rayon::iter::*;
 
+use crate::cache::*;
 use crate::check_external_cmds::*;
+use crate::elf::*;
+use crate::history::*;
+use crate::libs::*;
+use crate::loader::*;
+use crate::packages::*;
 use crate::parse::*;
+use crate::relink::*;
+use crate::script::*;
+use crate::smoke::*;
+use crate::wrapper::*;
 
 struct Output {
     stdout: String,
@@ -22,11 +39,112 @@ impl Output {
     }
 }
 
-pub(crate) fn run_cargo_install<'a>(
-    binary: &'a str,
-    cargo_args: &[&str],
-    list_of_failures: &mut Vec<&'a str>,
-) {
+// order in which the checked crates are listed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SortOrder {
+    // alphabetically
+    Name,
+    // broken crates first, then by name
+    Status,
+    // biggest binaries first, then by name
+    Size,
+}
+
+// how broken crates get fixed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FixStrategy {
+    // cargo install the crate again
+    Rebuild,
+    // point the binaries at an installed toolchain that still has the libraries they miss,
+    // crates that can't be fixed that way get rebuilt
+    Relink,
+    // like relink, but leave the binaries alone and run them through a script that sets
+    // LD_LIBRARY_PATH
+    Wrapper,
+}
+
+// what git crates get rebuilt from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GitPolicy {
+    // the commit that was installed, it is known to have built once
+    Exact,
+    // the branch or tag the crate was installed from, as it is now
+    Follow,
+}
+
+impl Default for GitPolicy {
+    fn default() -> Self {
+        GitPolicy::Exact
+    }
+}
+
+// how crates get checked and reported, from the command line
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CheckOptions {
+    // don't check anything, every crate needs a rebuild
+    pub(crate) rebuild_all: bool,
+    // also look for undefined symbols
+    pub(crate) deep: bool,
+    // with rebuild_all, leave crates with only static binaries alone
+    pub(crate) skip_static: bool,
+    // describe every binary
+    pub(crate) verbose: bool,
+    // None in diff mode, nothing gets listed then
+    pub(crate) print_order: Option<SortOrder>,
+    // pretend the libraries in this directory are gone, to see what removing a toolchain breaks
+    pub(crate) removed_lib_dir: Option<PathBuf>,
+    // passed on to "cargo install" when rebuilding, like "--locked"
+    pub(crate) cargo_flags: Vec<String>,
+    pub(crate) git_policy: GitPolicy,
+    // also run the binaries whose links are fine, to catch those that fail at runtime;
+    // None without --smoke
+    pub(crate) smoke: Option<SmokeConfig>,
+}
+
+// what is wrong with a single binary, this is what gets cached
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct BinaryCheck {
+    pub(crate) missing_libs: Vec<String>,
+    // (library, version), for example ("libc.so.6", "GLIBC_2.28")
+    pub(crate) missing_versions: Vec<(String, String)>,
+    // (library, symbol) with --deep, the library is only known for versioned symbols
+    pub(crate) missing_symbols: Vec<(Option<String>, String)>,
+    // None if the binary is not an ELF file
+    pub(crate) elf: Option<ElfInfo>,
+}
+
+// outcome of checking a single crate, printed once all crates are checked
+pub(crate) struct CrateReport<'a> {
+    pub(crate) package: &'a CrateInfo,
+    pub(crate) broken: bool,
+    // combined size of the installed binaries in bytes
+    size: u64,
+    missing_libs: Vec<String>,
+    // a library is found, but it is not the one the crate was built against
+    abi_mismatch: bool,
+    // built for another machine or C library
+    foreign: bool,
+    // a script whose interpreter is gone
+    broken_script: bool,
+    // a file in bin/ that is neither an ELF binary nor a script, like a truncated download
+    unknown_format: bool,
+    // every link is fine, but a binary fails when it is run (--smoke)
+    pub(crate) runtime_broken: bool,
+    // some binaries only work through wrappers we installed
+    pub(crate) wrapped: bool,
+    // every binary is statically linked, so there are no links that could break
+    pub(crate) static_only: bool,
+    output: Output,
+}
+
+// outcome of reinstalling a single crate
+pub(crate) struct RebuildResult<'a> {
+    pub(crate) package: &'a CrateInfo,
+    pub(crate) duration: Duration,
+    pub(crate) success: bool,
+}
+
+pub(crate) fn run_cargo_install(binary: &str, cargo_args: &[&str]) -> bool {
     println!("  Reinstalling {}", binary);
     let mut cargo = Command::new("cargo");
     cargo.arg("install");
@@ -39,17 +157,22 @@ pub(crate) fn run_cargo_install<'a>(
         }
     }
 
-    if let Ok(status) = cargo.status() {
+    match cargo.status() {
         // bad exit status of cargo, build failed?
-        if !status.success() {
-            list_of_failures.push(binary);
-        }
-    } else {
+        Ok(status) => status.success(),
         // maybe cargo crashed?
-        list_of_failures.push(binary);
+        Err(_) => false,
     }
 }
 
+pub(crate) fn failed_rebuilds<'a>(rebuilds: &[RebuildResult<'a>]) -> Vec<&'a str> {
+    rebuilds
+        .iter()
+        .filter(|rebuild| !rebuild.success)
+        .map(|rebuild| rebuild.package.name.as_str())
+        .collect()
+}
+
 fn check_bin_with_ldd(binary_path: &str, rustc_lib_path: &str) -> String {
     // checks a single binary with ldd
     let result = Command::new("ldd")
@@ -73,49 +196,291 @@ fn check_bin_with_ldd(binary_path: &str, rustc_lib_path: &str) -> String {
     }
 }
 
-fn parse_ldd_output<'a>(
+fn parse_ldd_output(ldd_result: &str) -> Vec<String> {
+    // receive the output of ldd, parse it and collect the libraries that could not be found
+    ldd_result
+        .lines()
+        // is binary missing a library?
+        .filter(|line| line.ends_with("=> not found"))
+        .map(|line| line.replace("=> not found", "").trim().to_string())
+        .collect()
+}
+
+fn parse_ldd_resolved(ldd_result: &str) -> Vec<(String, PathBuf)> {
+    // the libraries ldd found: "libc.so.6 => /usr/lib/libc.so.6 (0x00007f2367057000)"
+    ldd_result
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            let (name, rest) = match line.find(" => ") {
+                Some(position) => (&line[..position], &line[position + 4..]),
+                // the dynamic loader is only listed by its path
+                None => {
+                    let path = Path::new(line.split_whitespace().next()?);
+                    (path.file_name()?.to_str()?, line)
+                }
+            };
+            let path = rest.split_whitespace().next()?;
+            // "not found" or linux-vdso.so.1, which has no file
+            if !path.starts_with('/') {
+                return None;
+            }
+            Some((name.to_string(), PathBuf::from(path)))
+        })
+        .collect()
+}
+
+// every library of a binary as the loader resolves it for check_binary(), None if it was not found
+pub(crate) fn resolve_libraries(
+    binary_path: &Path,
+    rustc_lib_path: &str,
+) -> Vec<(String, Option<PathBuf>)> {
+    let ldd_result = check_bin_with_ldd(binary_path.to_str().unwrap(), rustc_lib_path);
+    ldd_libraries(&ldd_result)
+}
+
+fn ldd_libraries(ldd_result: &str) -> Vec<(String, Option<PathBuf>)> {
+    let mut libraries = parse_ldd_resolved(ldd_result)
+        .into_iter()
+        .map(|(name, path)| (name, Some(path)))
+        .collect::<Vec<_>>();
+    libraries.extend(
+        parse_ldd_output(ldd_result)
+            .into_iter()
+            .map(|name| (name, None)),
+    );
+    libraries
+}
+
+fn missing_symbol_versions(binary: &Elf, resolved: &[(String, PathBuf)]) -> Vec<(String, String)> {
+    // compare the symbol versions the binary needs with the ones its libraries define
+    let requirements = match binary.version_requirements() {
+        Ok(requirements) => requirements,
+        Err(_) => return Vec::new(),
+    };
+    let mut missing = Vec::new();
+    for (library, versions) in requirements {
+        // libraries that were not found at all are reported already
+        let path = match resolved.iter().find(|(name, _)| *name == library) {
+            Some((_, path)) => path,
+            None => continue,
+        };
+        let definitions = match Elf::read(path).and_then(|elf| elf.version_definitions()) {
+            Ok(definitions) => definitions,
+            Err(_) => continue,
+        };
+        for version in versions {
+            if !definitions.contains(&version) {
+                missing.push((library.clone(), version));
+            }
+        }
+    }
+    missing
+}
+
+fn missing_symbols(binary: &Elf, resolved: &[(String, PathBuf)]) -> Vec<(Option<String>, String)> {
+    // like "ldd -r": every undefined symbol has to be exported by some library ldd found
+    let undefined = match binary.undefined_symbols() {
+        Ok(undefined) => undefined,
+        Err(_) => return Vec::new(),
+    };
+    let mut exported = HashSet::new();
+    for (_, path) in resolved {
+        if let Ok(symbols) = Elf::read(path).and_then(|elf| elf.exported_symbols()) {
+            exported.extend(symbols);
+        }
+    }
+    let mut missing = undefined
+        .into_iter()
+        .filter(|(symbol, _)| !exported.contains(symbol))
+        .map(|(symbol, library)| (library, symbol))
+        .collect::<Vec<_>>();
+    missing.sort();
+    missing
+}
+
+fn check_binary(binary_path: &Path, rustc_lib_path: &str, options: &CheckOptions) -> BinaryCheck {
+    // not something we can look into if this fails, ldd tells us what it can
+    let binary = Elf::read(binary_path).ok();
+    let elf = binary.as_ref().and_then(|binary| binary.info().ok());
+    // static binaries have no links that could break, ldd would only tell us
+    // "not a dynamic executable" or "statically linked"
+    if elf.as_ref().map(ElfInfo::is_static) == Some(true) {
+        return BinaryCheck {
+            elf,
+            ..BinaryCheck::default()
+        };
+    }
+
+    // run ldd on it and check ldds output
+    let ldd_result = check_bin_with_ldd(binary_path.to_str().unwrap(), rustc_lib_path);
+    let mut resolved = parse_ldd_resolved(&ldd_result);
+    let mut missing_libs = parse_ldd_output(&ldd_result);
+    if let Some(ref removed_lib_dir) = options.removed_lib_dir {
+        // the loader looks further once the dir is gone, and may end up somewhere else
+        let libraries = ldd_libraries(&ldd_result);
+        let libraries = match dependency_tree(
+            binary_path,
+            &libraries,
+            rustc_lib_path,
+            Some(removed_lib_dir),
+        ) {
+            Ok(tree) => all_libraries(&tree),
+            Err(_) => libraries
+                .into_iter()
+                .map(|(name, path)| (name, path.filter(|path| !path.starts_with(removed_lib_dir))))
+                .collect(),
+        };
+        resolved = Vec::new();
+        missing_libs = Vec::new();
+        for (name, path) in libraries {
+            match path {
+                Some(path) => resolved.push((name, path)),
+                None => missing_libs.push(name),
+            }
+        }
+    }
+    let (missing_versions, missing_symbols) = match binary {
+        Some(ref binary) if options.deep => (
+            missing_symbol_versions(binary, &resolved),
+            missing_symbols(binary, &resolved),
+        ),
+        Some(ref binary) => (missing_symbol_versions(binary, &resolved), Vec::new()),
+        None => (Vec::new(), Vec::new()),
+    };
+    BinaryCheck {
+        missing_libs,
+        missing_versions,
+        missing_symbols,
+        elf,
+    }
+}
+
+fn is_static_binary(binary_path: &Path) -> bool {
+    Elf::read(binary_path)
+        .and_then(|elf| elf.info())
+        .map(|info| info.is_static())
+        == Ok(true)
+}
+
+fn report_missing_symbols<'a>(
     output_string: &mut Output,
-    ldd_result: &str,
+    missing_symbols: &[(Option<String>, String)],
     binary: &str,
     package: &'a CrateInfo,
 ) -> Option<&'a CrateInfo> {
-    // receive the output of ldd, parse it, print information on missing libraries to stderr
-    // and mark the crate as outdated if it is
+    if missing_symbols.is_empty() {
+        return None;
+    }
 
-    // assume package is not outdated
-    let mut outdated_package: Option<&CrateInfo> = None;
+    output_string.stderr.push_str(&format!(
+        "    Binary '{}' has undefined symbols no library provides:\n",
+        binary
+    ));
+    // sorted by library, so one line per library
+    let mut index = 0;
+    while index < missing_symbols.len() {
+        let library = &missing_symbols[index].0;
+        let symbols = missing_symbols[index..]
+            .iter()
+            .take_while(|(other, _)| other == library)
+            .map(|(_, symbol)| symbol.as_str())
+            .collect::<Vec<_>>();
+        index += symbols.len();
+        output_string.stderr.push_str(&format!(
+            "\t\t{}: {}\n",
+            library.as_ref().map_or("any library", String::as_str),
+            symbols.join(" ")
+        ));
+    }
+    Some(package)
+}
+
+fn report_missing_versions<'a>(
+    output_string: &mut Output,
+    missing_versions: &[(String, String)],
+    binary: &str,
+    package: &'a CrateInfo,
+) -> Option<&'a CrateInfo> {
+    if missing_versions.is_empty() {
+        return None;
+    }
 
-    // we need to know if this is the first missing lib of a binary when making our output string
-    let output = ldd_result;
+    output_string.stderr.push_str(&format!(
+        "    Binary '{}' needs symbol versions its libraries don't provide:\n",
+        binary
+    ));
+    for (library, version) in missing_versions {
+        output_string
+            .stderr
+            .push_str(&format!("\t\t{} ({})\n", version, library));
+    }
+    Some(package)
+}
 
-    let mut first_broken_link = true;
-    for line in output.lines() {
-        // is binary missing a library?
-        if line.ends_with("=> not found") {
-            if first_broken_link {
-                // we found a broken library link, assume package is outdated
-                outdated_package = Some(package);
-                output_string
-                    .stderr
-                    .push_str(&format!("    Binary '{}' is missing:\n", &binary));
-            }
-            output_string.stderr.push_str(&format!(
-                "\t\t{}\n",
-                line.replace("=> not found", "").trim()
-            ));
-            first_broken_link = false;
-        } // not found
-    } // for line in output.lines()
+fn report_foreign_binary<'a>(
+    output_string: &mut Output,
+    elf: &ElfInfo,
+    binary: &str,
+    package: &'a CrateInfo,
+) -> Option<&'a CrateInfo> {
+    // binaries copied from another machine or built for another target
+    let mut foreign = false;
+    if elf.is_foreign() {
+        output_string.stderr.push_str(&format!(
+            "    Binary '{}' is built for {}, this system runs {}\n",
+            binary,
+            elf.arch(),
+            ElfInfo::host_arch().unwrap_or_default()
+        ));
+        foreign = true;
+    }
+    if let Some(interpreter) = elf.missing_interpreter() {
+        output_string.stderr.push_str(&format!(
+            "    Binary '{}' needs the interpreter '{}', which does not exist\n",
+            binary, interpreter
+        ));
+        foreign = true;
+    }
+    if foreign {
+        Some(package)
+    } else {
+        None
+    }
+}
 
-    outdated_package
+fn report_missing_libs<'a>(
+    output_string: &mut Output,
+    missing_libs: &[String],
+    binary: &str,
+    package: &'a CrateInfo,
+) -> Option<&'a CrateInfo> {
+    // print information on missing libraries to stderr and mark the crate as outdated if it is
+    if missing_libs.is_empty() {
+        return None;
+    }
+
+    // we found a broken library link, package is outdated
+    output_string
+        .stderr
+        .push_str(&format!("    Binary '{}' is missing:\n", binary));
+    for lib in missing_libs {
+        output_string.stderr.push_str(&format!(
+            "\t\t{} ({})\n",
+            lib,
+            classify_library(lib).label()
+        ));
+    }
+    Some(package)
 }
 
 pub(crate) fn check_crate<'a>(
     package: &'a CrateInfo,
-    bin_dir: &std::path::PathBuf,
+    bin_dir: &Path,
     rustc_lib_path: &str,
-    rebuild_all: bool,
-) -> Option<&'a CrateInfo> {
+    cache: Option<&Cache>,
+    options: &CheckOptions,
+) -> CrateReport<'a> {
     let mut output_string = Output::new();
 
     output_string.stdout.push_str(&format!(
@@ -124,30 +489,292 @@ pub(crate) fn check_crate<'a>(
     ));
 
     let mut outdated_package: Option<&CrateInfo> = None;
+    let mut size = 0;
+    // missing libraries of all binaries, to suggest a fix once per crate
+    let mut crate_missing_libs: Vec<String> = Vec::new();
+    let mut abi_mismatch = false;
+    let mut foreign = false;
+    let mut broken_script = false;
+    let mut unknown_format = false;
+    let mut wrapped = false;
+    let mut runtime_broken = false;
+    let mut static_binaries = 0;
+    let smoke_args = match options.smoke {
+        Some(ref smoke) if !options.rebuild_all => smoke.args(&package.name),
+        _ => None,
+    };
 
     for binary in &package.binaries {
-        if rebuild_all {
-            // rebuild unconditionally
-            outdated_package = Some(package);
+        // fuse together the path to the binary we are going to check and get its String
+        let bin_path = bin_dir.join(binary);
+        if let Ok(metadata) = fs::metadata(&bin_path) {
+            size += metadata.len();
+        }
+        if options.rebuild_all {
+            // rebuild unconditionally, unless we were told to skip static binaries
+            let is_static = is_static_binary(&bin_path);
+            if is_static {
+                static_binaries += 1;
+            }
+            if !(options.skip_static && is_static) {
+                outdated_package = Some(package);
+            }
         } else {
-            // fuse together the path to the binary we are going to check and get its String
-            let mut bin_path: std::path::PathBuf = bin_dir.clone();
-            bin_path.push(&binary);
-            let binary_path = bin_path.into_os_string().into_string().unwrap();
-            // run ldd on it and check ldds output
-            let ldd_result = check_bin_with_ldd(&binary_path, rustc_lib_path);
-            outdated_package = parse_ldd_output(&mut output_string, &ldd_result, binary, package);
+            if !bin_path.is_file() {
+                // the binary was deleted, reinstalling the crate brings it back
+                output_string.stderr.push_str(&format!(
+                    "    Binary '{}' not found in '{}'\n",
+                    binary,
+                    bin_dir.display()
+                ));
+                outdated_package = Some(package);
+                continue;
+            }
+            // a wrapper we installed runs the real binary with more lib dirs, ldd has nothing
+            // to say about other scripts, but their interpreter can go missing
+            let wrapper = wrapped_binary(bin_dir, binary);
+            if let Some(ref wrapper) = wrapper {
+                output_string.stdout.push_str(&format!(
+                    "    Binary '{}' is wrapped, it runs with LD_LIBRARY_PATH={}\n",
+                    binary,
+                    wrapper.library_path("")
+                ));
+                wrapped = true;
+            } else if let Some(shebang) = read_shebang(&bin_path) {
+                if options.verbose {
+                    output_string.stdout.push_str(&format!(
+                        "    Binary '{}': script, interpreter {}\n",
+                        binary, shebang.interpreter
+                    ));
+                }
+                if let Some(problem) = shebang.problem() {
+                    output_string
+                        .stderr
+                        .push_str(&format!("    {}\n", problem.describe(binary)));
+                    outdated_package = Some(package);
+                    broken_script = true;
+                }
+                continue;
+            } else if !is_elf_file(&bin_path) {
+                // ldd only says "not a dynamic executable" about these
+                output_string.stderr.push_str(&format!(
+                    "    Binary '{}' is neither an ELF binary nor a script\n",
+                    binary
+                ));
+                outdated_package = Some(package);
+                unknown_format = true;
+                continue;
+            }
+            // symbols are not cached, so --deep checks every binary again, and wrapped or
+            // relinked binaries break when their toolchain goes, which the cache doesn't notice
+            let relinked = is_relinked(bin_dir, binary);
+            let cached = if options.deep || wrapper.is_some() || relinked {
+                None
+            } else {
+                cache.and_then(|cache| cache.lookup(&bin_path))
+            };
+            let check = match (cached, wrapper) {
+                // we already know this binary
+                (Some(check), _) => check,
+                (None, Some(wrapper)) => check_binary(
+                    &wrapper.real,
+                    &wrapper.library_path(rustc_lib_path),
+                    options,
+                ),
+                (None, None) => {
+                    let check = check_binary(&bin_path, rustc_lib_path, options);
+                    if let (Some(cache), false) = (cache, relinked) {
+                        cache.insert(&bin_path, &check);
+                    }
+                    check
+                }
+            };
+            if let Some(ref elf) = check.elf {
+                if elf.is_static() {
+                    static_binaries += 1;
+                }
+                if options.verbose {
+                    output_string
+                        .stdout
+                        .push_str(&format!("    Binary '{}': {}\n", binary, elf));
+                }
+                if let Some(pkg) = report_foreign_binary(&mut output_string, elf, binary, package) {
+                    outdated_package = Some(pkg);
+                    foreign = true;
+                }
+            }
+            crate_missing_libs.extend(check.missing_libs.iter().cloned());
+            // don't let a healthy binary hide a broken one we found before
+            if let Some(pkg) =
+                report_missing_libs(&mut output_string, &check.missing_libs, binary, package)
+            {
+                outdated_package = Some(pkg);
+            }
+            if let Some(pkg) = report_missing_versions(
+                &mut output_string,
+                &check.missing_versions,
+                binary,
+                package,
+            ) {
+                outdated_package = Some(pkg);
+                abi_mismatch = true;
+            }
+            if let Some(pkg) =
+                report_missing_symbols(&mut output_string, &check.missing_symbols, binary, package)
+            {
+                outdated_package = Some(pkg);
+                abi_mismatch = true;
+            }
+            // only binaries that link fine are worth running, the others are known to fail
+            let links_fine = check.missing_libs.is_empty()
+                && check.missing_versions.is_empty()
+                && check.missing_symbols.is_empty()
+                && check.elf.as_ref().map_or(true, |elf| {
+                    !elf.is_foreign() && elf.missing_interpreter().is_none()
+                });
+            if let (true, Some(args)) = (links_fine, &smoke_args) {
+                if let Err(failure) = smoke_test(&bin_path, args, rustc_lib_path) {
+                    output_string
+                        .stderr
+                        .push_str(&format!("    {}\n", failure.describe(binary, args)));
+                    runtime_broken = true;
+                }
+            }
+        }
+    }
+    crate_missing_libs.sort();
+    crate_missing_libs.dedup();
+    CrateReport {
+        package,
+        broken: outdated_package.is_some() || runtime_broken,
+        size,
+        missing_libs: crate_missing_libs,
+        abi_mismatch,
+        foreign,
+        broken_script,
+        unknown_format,
+        runtime_broken: runtime_broken && outdated_package.is_none(),
+        wrapped,
+        static_only: !package.binaries.is_empty() && static_binaries == package.binaries.len(),
+        output: output_string,
+    }
+}
+
+fn sort_reports(reports: &mut [CrateReport<'_>], sort_order: SortOrder) {
+    // the name (and version, for crates installed twice under different names) breaks ties,
+    // so the order never depends on which thread finished first
+    reports.sort_by(|a, b| {
+        let by_name = a
+            .package
+            .name
+            .cmp(&b.package.name)
+            .then(a.package.version.cmp(&b.package.version));
+        match sort_order {
+            SortOrder::Name => by_name,
+            SortOrder::Status => b.broken.cmp(&a.broken).then(by_name),
+            SortOrder::Size => b.size.cmp(&a.size).then(by_name),
+        }
+    });
+}
+
+fn suggested_fixes(report: &CrateReport<'_>, packages: Option<&PackageIndex>) -> Vec<String> {
+    let missing_libs = &report.missing_libs;
+    let mut kinds = missing_libs
+        .iter()
+        .map(|lib| classify_library(lib))
+        .collect::<Vec<_>>();
+    kinds.sort();
+    kinds.dedup();
+
+    let mut fixes = Vec::new();
+    for kind in kinds {
+        if let (LibraryKind::System, Some(packages)) = (kind, packages) {
+            // name the packages if the package database knows them
+            let suggestions = missing_libs
+                .iter()
+                .filter(|lib| classify_library(lib) == LibraryKind::System)
+                .map(|lib| packages.suggestion(lib))
+                .collect::<Vec<_>>();
+            let mut installs = suggestions
+                .iter()
+                .filter_map(|suggestion| suggestion.clone())
+                .collect::<Vec<_>>();
+            installs.sort();
+            installs.dedup();
+            if !installs.is_empty() {
+                fixes.push(format!("{}, then rebuild the crate", installs.join(", ")));
+            }
+            if suggestions.iter().all(Option::is_some) {
+                continue;
+            }
         }
+        fixes.push(kind.remedy().to_string());
+    }
+    if report.abi_mismatch {
+        fixes.push(String::from(
+            "rebuild the crate against the installed versions of its libraries",
+        ));
+    }
+    if report.foreign {
+        fixes.push(String::from("rebuild the crate on this machine"));
+    }
+    if report.broken_script {
+        fixes.push(String::from(
+            "install the interpreter the script asks for, or rebuild the crate",
+        ));
     }
-    // print to stdout/stderr respectively
-    // don't print empty lines!
-    if !output_string.stdout.is_empty() {
-        print!("{}", &output_string.stdout);
+    if report.unknown_format {
+        fixes.push(String::from(
+            "rebuild the crate, some of its binaries are damaged or were replaced",
+        ));
     }
-    if !output_string.stderr.is_empty() {
-        eprint!("{}", &output_string.stderr);
+    if report.runtime_broken {
+        fixes.push(String::from(
+            "rebuild the crate, its binaries link fine but fail when they are run",
+        ));
+    }
+    fixes
+}
+
+pub(crate) fn print_reports(reports: &[CrateReport<'_>]) {
+    // only read the package database if it has something to tell us
+    let system_libs = reports
+        .iter()
+        .flat_map(|report| report.missing_libs.iter())
+        .filter(|lib| classify_library(lib) == LibraryKind::System)
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    let packages = if system_libs.is_empty() {
+        None
+    } else {
+        PackageIndex::load(Path::new("/"), &system_libs)
+    };
+
+    for report in reports {
+        // print to stdout/stderr respectively
+        // don't print empty lines!
+        if !report.output.stdout.is_empty() {
+            print!("{}", report.output.stdout);
+            // keep the details on stderr next to the crate they belong to
+            let _ = io::stdout().flush();
+        }
+        if !report.output.stderr.is_empty() {
+            eprint!("{}", report.output.stderr);
+        }
+        for fix in suggested_fixes(report, packages.as_ref()) {
+            eprintln!("    To fix: {}", fix);
+        }
+    }
+}
+
+pub(crate) fn print_crate_list(packages: &[CrateInfo]) {
+    let mut packages = packages.iter().collect::<Vec<_>>();
+    packages.sort_by(|a, b| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+    for package in packages {
+        println!("  {} {}", package.name, package.version);
+        println!("    Source: {}", package.source());
+        println!("    Binaries: {}", package.binaries.join(" "));
     }
-    outdated_package
 }
 
 pub(crate) fn get_rustc_lib_path() -> String {
@@ -176,98 +803,297 @@ pub(crate) fn get_rustc_lib_path() -> String {
         .expect("Failed to convert pathBuf to String")
 }
 
-pub(crate) fn check_and_rebuild_broken_crates(
-    packages: &[CrateInfo],
+pub(crate) fn get_rustc_version() -> String {
+    // "rustc 1.29.0-nightly (4f3c7a472 2018-07-17)"
+    let rustc = get_rustc();
+    match Command::new(&rustc)
+        .arg("--version")
+        .env("LANG", "en_US")
+        .env("LC_ALL", "en_US")
+        .output()
+    {
+        Ok(out) => String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        Err(e) => panic!("Error getting rustc version '{}'", e),
+    }
+}
+
+pub(crate) fn get_rustup_toolchains_dir() -> Option<PathBuf> {
+    // $RUSTUP_HOME/toolchains, RUSTUP_HOME defaults to ~/.rustup
+    let rustup_home = match env::var_os("RUSTUP_HOME") {
+        Some(rustup_home) => PathBuf::from(rustup_home),
+        None => {
+            let mut home = PathBuf::from(env::var_os("HOME")?);
+            home.push(".rustup");
+            home
+        }
+    };
+    let toolchains_dir = rustup_home.join("toolchains");
+    if toolchains_dir.is_dir() {
+        Some(toolchains_dir)
+    } else {
+        None
+    }
+}
+
+// the directory of an installed toolchain, "nightly" is enough for "nightly-x86_64-unknown-linux-gnu"
+pub(crate) fn find_toolchain(name: &str) -> Option<PathBuf> {
+    let toolchains_dir = get_rustup_toolchains_dir()?;
+    if toolchains_dir.join(name).is_dir() {
+        return Some(toolchains_dir.join(name));
+    }
+    let prefix = format!("{}-", name);
+    let mut matches = fs::read_dir(&toolchains_dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.starts_with(&prefix))
+                    == Some(true)
+        })
+        .collect::<Vec<_>>();
+    // "stable" must not pick one of several stable toolchains for other targets
+    if matches.len() == 1 {
+        matches.pop()
+    } else {
+        None
+    }
+}
+
+pub(crate) fn check_crates<'a>(
+    packages: &'a [CrateInfo],
     rust_lib_path: &str,
-    bin_dir: &std::path::PathBuf,
-    do_auto_rebuild: bool,
-    rebuild_all: bool,
-) {
+    bin_dir: &Path,
+    cache: Option<&Cache>,
+    options: &CheckOptions,
+) -> Vec<CrateReport<'a>> {
     // iterate (in parallel) over the acquired metadata and check for broken library links
-    // filter out all None values, only collect the Some() ones
-
-    let broken_pkgs: Vec<&CrateInfo> = packages
+    let mut reports = packages
         .par_iter()
-        .filter_map(|crate_data| check_crate(crate_data, bin_dir, rust_lib_path, rebuild_all))
+        .map(|crate_data| check_crate(crate_data, bin_dir, rust_lib_path, cache, options))
+        .collect::<Vec<_>>();
+    sort_reports(&mut reports, options.print_order.unwrap_or(SortOrder::Name));
+    reports
+}
+
+// check the crates as they are and once more with simulated_lib_path as the sysroot and without
+// options.removed_lib_dir, returns the crates that would break and the ones that would be fixed
+pub(crate) fn simulate_crates<'a>(
+    packages: &'a [CrateInfo],
+    rust_lib_path: &str,
+    simulated_lib_path: &str,
+    bin_dir: &Path,
+    cache: Option<&Cache>,
+    options: &CheckOptions,
+) -> (Vec<&'a CrateInfo>, Vec<&'a CrateInfo>) {
+    let current_options = CheckOptions {
+        removed_lib_dir: None,
+        ..options.clone()
+    };
+    let current = check_crates(packages, rust_lib_path, bin_dir, cache, &current_options);
+    // made up results must not end up in the cache
+    let simulated = check_crates(packages, simulated_lib_path, bin_dir, None, options);
+    let broken_now = broken_crates(&current);
+    let broken_then = broken_crates(&simulated);
+    let contains =
+        |list: &[&CrateInfo], package: &CrateInfo| list.iter().any(|pkg| ptr::eq(*pkg, package));
+
+    let fixed = broken_now
+        .iter()
+        .filter(|pkg| !contains(&broken_then, pkg))
+        .cloned()
         .collect();
+    // tell why they would break
+    let would_break = simulated
+        .into_iter()
+        .filter(|report| report.broken && !contains(&broken_now, report.package))
+        .collect::<Vec<_>>();
+    print_reports(&would_break);
+    (broken_crates(&would_break), fixed)
+}
+
+pub(crate) fn broken_crates<'a>(reports: &[CrateReport<'a>]) -> Vec<&'a CrateInfo> {
+    reports
+        .iter()
+        .filter(|report| report.broken)
+        .map(|report| report.package)
+        .collect()
+}
+
+// the "cargo install" flags that pick the commit of a git crate
+fn git_ref_args(pkg: &CrateInfo, policy: GitPolicy) -> Vec<&str> {
+    let mut args = Vec::new();
+    match (policy, &pkg.commit) {
+        // the commit that was installed, wherever its branch or tag has moved since
+        (GitPolicy::Exact, Some(commit)) => {
+            args.push("--rev");
+            args.push(commit.as_str());
+        }
+        // we have a git package, check if it has branch, tag or rev, else install from repo
+        _ => {
+            if let Some(ref branch) = pkg.branch {
+                args.push("--branch");
+                args.push(branch);
+            }
+            if let Some(ref tag) = pkg.tag {
+                args.push("--tag");
+                args.push(tag);
+            }
+            if let Some(ref rev) = pkg.rev {
+                args.push("--rev");
+                args.push(rev);
+            }
+        }
+    }
+    args
+}
+
+pub(crate) fn rebuild_crates<'a>(
+    broken_pkgs: &[&'a CrateInfo],
+    install_root: &Path,
+    options: &CheckOptions,
+) -> Vec<RebuildResult<'a>> {
+    // reinstall crates into the root they came from
+    let install_root = install_root.display().to_string();
+    let mut rebuilds = Vec::with_capacity(broken_pkgs.len());
+
+    // we need to find out if a package is a git package
+    for pkg in broken_pkgs {
+        // crates from other registries than crates.io are reinstalled by registry name
+        let registry_name = match pkg.registry {
+            Some(ref registry) if pkg.git.is_none() && registry != CRATES_IO_INDEX => {
+                let name = registry_name(registry)
+                    .ok_or_else(|| {
+                        format!("no registry in the cargo config has the index {}", registry)
+                    })
+                    .and_then(|name| check_cargo_flag("--registry", cargo_version()).map(|_| name));
+                match name {
+                    Ok(name) => Some(name),
+                    Err(reason) => {
+                        println!("  Can't reinstall {}: {}", pkg.name, reason);
+                        rebuilds.push(RebuildResult {
+                            package: pkg,
+                            duration: Duration::from_secs(0),
+                            success: false,
+                        });
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        // read the line saved in .crates.toml and find out the according "cargo install" flags
+        let mut cargo_args: Vec<&str> = Vec::with_capacity(8);
+        cargo_args.push("--root");
+        cargo_args.push(&install_root);
+        cargo_args.extend(options.cargo_flags.iter().map(String::as_str));
+        if let Some(ref git_repo_addr) = pkg.git {
+            cargo_args.push("--git");
+            cargo_args.push(git_repo_addr);
+            cargo_args.extend(git_ref_args(pkg, options.git_policy));
+        } else {
+            // registry package, reinstall the same version
+            if pkg.registry.is_some() {
+                if let Some(ref name) = registry_name {
+                    cargo_args.push("--registry");
+                    cargo_args.push(name);
+                }
+                cargo_args.push("--version");
+                cargo_args.push(&pkg.version);
+            } // match pkg.registry
+              // if we just have a path, there's not much we can do, I guess...
+            if let Some(ref path) = pkg.path {
+                cargo_args.push("--path");
+                cargo_args.push(path);
+            } // match pkg.path
+        } // if let Some(ref git_repo_addr) = pkg.git
+
+        let started = Instant::now();
+        let success = run_cargo_install(&pkg.name, &cargo_args);
+        rebuilds.push(RebuildResult {
+            package: pkg,
+            duration: started.elapsed(),
+            success,
+        });
+    }
+    rebuilds
+}
+
+pub(crate) fn check_and_rebuild_broken_crates(
+    packages: &[CrateInfo],
+    rust_lib_path: &str,
+    install_root: &Path,
+    cache: Option<&Cache>,
+    history: &mut HistoryRecord,
+    fix: Option<FixStrategy>,
+    options: &CheckOptions,
+) -> bool {
+    // returns false if any rebuild failed
+    let bin_dir = install_root.join("bin");
+    let reports = check_crates(packages, rust_lib_path, &bin_dir, cache, options);
+    let broken_pkgs = broken_crates(&reports);
+    history.add_crates(install_root, &reports, options.rebuild_all);
 
     let rebuilds_required: bool = !broken_pkgs.is_empty();
 
-    if rebuilds_required {
-        // concat list of names of crates needing rebuilding
-        if rebuild_all {
-            println!("\n  Rebuilding all installed crates as requested.");
+    // in diff mode only the changes since the last run get reported
+    if options.print_order.is_some() {
+        print_reports(&reports);
+        if !rebuilds_required {
+            // if all crates have working links, no need to do anything else
+            println!("\n  Everything looks good! :)");
         } else {
+            // concat list of names of crates needing rebuilding
             let pkgs_string = &broken_pkgs
                 .iter()
                 .map(|pkg| pkg.name.clone())
                 .collect::<Vec<_>>()
                 .join(" ");
 
-            println!("\n  Crates needing rebuild: {}", pkgs_string);
-        }
-    } else {
-        // if all crates have working links, no need to do anything else
-        println!("\n  Everything looks good! :)");
-        std::process::exit(0);
-    }
-
-    let mut list_of_failures: Vec<&str> = Vec::with_capacity(broken_pkgs.len());
-    // try to rebuild broken packages
-    if rebuilds_required && (do_auto_rebuild || rebuild_all) {
-        // we need to find out if a package is a git package
-        for pkg in broken_pkgs {
-            // read the line saved in .crates.toml and find out the according "cargo install" flags
-            let mut cargo_args: Vec<&str> = Vec::with_capacity(6);
-            if let Some(ref git_repo_addr) = pkg.git {
-                cargo_args.push("--git");
-                cargo_args.push(git_repo_addr);
-
-                // we have a git package, check if it has branch, tag or rev, else install from repo
-                if let Some(ref branch) = pkg.branch {
-                    cargo_args.push("--branch");
-                    cargo_args.push(branch);
-                }
-                if let Some(ref tag) = pkg.tag {
-                    cargo_args.push("--tag");
-                    cargo_args.push(tag);
-                }
-                if let Some(ref rev) = pkg.rev {
-                    cargo_args.push("--rev");
-                    cargo_args.push(rev);
-                }
+            if options.rebuild_all {
+                println!("\n  Rebuilding as requested: {}", pkgs_string);
             } else {
-                // normal crates.io package?
-                if let Some(ref registry) = pkg.registry {
-                    if registry == "https://github.com/rust-lang/crates.io-index" {
-                        // crates io, reinstall the same version
-                        cargo_args.push("--version");
-                        cargo_args.push(&pkg.version);
-                    } else {
-                        eprintln!("error unknown registry!");
-                        panic!();
-                    }
-                } // match pkg.registry
-                  // if we just have a path, there's not much we can do, I guess...
-                if let Some(ref path) = pkg.path {
-                    cargo_args.push("--path");
-                    cargo_args.push(path);
-                } // match pkg.path
-            } // if let Some(ref git_repo_addr) = pkg.git
-
-            run_cargo_install(&pkg.name, &cargo_args, &mut list_of_failures);
+                println!("\n  Crates needing rebuild: {}", pkgs_string);
+            }
         }
     }
-    if !list_of_failures.is_empty() {
-        println!("    Failed rebuilds: {}", list_of_failures.join(" "));
-        std::process::exit(4);
+    if !rebuilds_required {
+        return true;
     }
+
+    // try to fix broken packages
+    if fix.is_some() || options.rebuild_all {
+        // crates to rebuild were not checked, there is nothing to relink them to
+        let broken_pkgs = match fix {
+            _ if options.rebuild_all => broken_pkgs,
+            Some(FixStrategy::Relink) => relink_crates(&broken_pkgs, install_root, rust_lib_path),
+            Some(FixStrategy::Wrapper) => wrap_crates(&broken_pkgs, install_root, rust_lib_path),
+            _ => broken_pkgs,
+        };
+        let rebuilds = rebuild_crates(&broken_pkgs, install_root, options);
+        for rebuild in rebuilds.iter().filter(|rebuild| rebuild.success) {
+            remove_wrappers(rebuild.package, &bin_dir);
+            remove_backups(rebuild.package, install_root);
+        }
+        history.add_rebuilds(install_root, &rebuilds);
+        let list_of_failures = failed_rebuilds(&rebuilds);
+        if !list_of_failures.is_empty() {
+            println!("    Failed rebuilds: {}", list_of_failures.join(" "));
+            return false;
+        }
+    }
+    true
 }
 
 #[cfg(test)]
 mod tests {
     use self::test::Bencher;
     use super::*;
+    use crate::test_helpers::*;
 
     #[test]
     fn package_needs_rebuild() {
@@ -294,19 +1120,20 @@ mod tests {
     /lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)\n";
 
         let our_formatted_output = "    Binary 'clippy-driver' is missing:
-\t\tlibrustc_driver-6516506ab0349d45.so
-\t\tlibrustc_plugin-14c7fbb709ee1764.so
-\t\tlibrustc_typeck-ca6d3c89de970134.so
-\t\tlibrustc-6b0d6e07668228e2.so
-\t\tlibsyntax-5ece0a81ed6c5461.so
-\t\tlibrustc_errors-7907d589f279528b.so
-\t\tlibsyntax_pos-610524479a0d36fa.so
-\t\tlibrustc_data_structures-b8a8de55dc5cd1ce.so
-\t\tlibstd-0cfbe79f10411924.so\n";
-
-        let parsed = parse_ldd_output(
+\t\tlibrustc_driver-6516506ab0349d45.so (rust toolchain)
+\t\tlibrustc_plugin-14c7fbb709ee1764.so (rust toolchain)
+\t\tlibrustc_typeck-ca6d3c89de970134.so (rust toolchain)
+\t\tlibrustc-6b0d6e07668228e2.so (rust toolchain)
+\t\tlibsyntax-5ece0a81ed6c5461.so (rust toolchain)
+\t\tlibrustc_errors-7907d589f279528b.so (rust toolchain)
+\t\tlibsyntax_pos-610524479a0d36fa.so (rust toolchain)
+\t\tlibrustc_data_structures-b8a8de55dc5cd1ce.so (rust toolchain)
+\t\tlibstd-0cfbe79f10411924.so (rust toolchain)\n";
+
+        let missing_libs = parse_ldd_output(ldd_output);
+        let parsed = report_missing_libs(
             &mut to_be_printed_string,
-            ldd_output,
+            &missing_libs,
             "clippy-driver",
             &clippy_crateinfo,
         );
@@ -347,9 +1174,10 @@ libc.so.6 => /usr/lib/libc.so.6 (0x00007f2367057000)
 libm.so.6 => /usr/lib/libm.so.6 (0x00007f2366d0b000)
 /lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)\n";
 
-        let parsed = parse_ldd_output(
+        let missing_libs = parse_ldd_output(ldd_output);
+        let parsed = report_missing_libs(
             &mut to_be_printed_string,
-            ldd_output,
+            &missing_libs,
             "clippy-driver",
             &clippy_crateinfo,
         );
@@ -357,6 +1185,398 @@ libm.so.6 => /usr/lib/libm.so.6 (0x00007f2366d0b000)
         assert!(to_be_printed_string.stderr.is_empty());
     }
 
+    #[test]
+    fn report_missing_system_library() {
+        let cargo_home = FakeCargoHome::new("system_library")
+            .registry_crate("tls", "0.4.0", &["tls", "tls-helper"])
+            // not known to any package database
+            .broken_binary("tls", "librebuild-check-test.so.1")
+            .broken_binary("tls-helper", "libtls-0123456789abcdef.so")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        let stderr = &output.stderr;
+        assert_eq!(
+            stderr,
+            "    Binary 'tls' is missing:
+\t\tlibrebuild-check-test.so.1 (system library)
+    Binary 'tls-helper' is missing:
+\t\tlibtls-0123456789abcdef.so (rust library)
+    To fix: rebuild the crate
+    To fix: install the package providing the library (and its -dev package), then rebuild the crate\n"
+        );
+    }
+
+    #[test]
+    fn resolved_libraries() {
+        let ldd_output = "\tlinux-vdso.so.1 (0x00007ffec37d0000)
+\tlibstd-0cfbe79f10411924.so => not found
+\tlibc.so.6 => /usr/lib/libc.so.6 (0x00007f2367057000)
+\t/lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)
+\t/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)\n";
+        assert_eq!(
+            parse_ldd_resolved(ldd_output),
+            vec![
+                (
+                    String::from("libc.so.6"),
+                    PathBuf::from("/usr/lib/libc.so.6")
+                ),
+                (
+                    String::from("/lib64/ld-linux-x86-64.so.2"),
+                    PathBuf::from("/usr/lib64/ld-linux-x86-64.so.2")
+                ),
+                (
+                    String::from("ld-linux-x86-64.so.2"),
+                    PathBuf::from("/lib64/ld-linux-x86-64.so.2")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn report_symbol_version_mismatch() {
+        let cargo_home = FakeCargoHome::new("version_mismatch")
+            .registry_crate("needy", "0.1.0", &["needy"])
+            .version_mismatch_binary("needy", "libversioned.so")
+            .registry_crate("optional", "0.1.0", &["optional"])
+            .weak_version_binary("optional", "liboptional.so")
+            .build();
+
+        // the loader does without versions that are only needed weakly
+        let output = cargo_home.run(&["--full"]);
+        let stdout = &output.stdout;
+        let stderr = &output.stderr;
+        assert_eq!(
+            stdout,
+            "  Checking crate needy 0.1.0\n  Checking crate optional 0.1.0\n
+  Crates needing rebuild: needy\n"
+        );
+        assert_eq!(
+            stderr,
+            "    Binary 'needy' needs symbol versions its libraries don't provide:
+\t\tVERS_2 (libversioned.so)
+    To fix: rebuild the crate against the installed versions of its libraries\n"
+        );
+    }
+
+    #[test]
+    fn report_missing_symbols_with_deep() {
+        let cargo_home = FakeCargoHome::new("missing_symbols")
+            .registry_crate("abi", "0.1.0", &["abi"])
+            .abi_changed_binary("abi", "libabi.so.1")
+            .build();
+
+        // ldd alone finds nothing wrong
+        let output = cargo_home.run(&["--full"]);
+        assert!(output.stderr.is_empty());
+
+        let output = cargo_home.run(&["--full", "--deep"]);
+        let stdout = &output.stdout;
+        let stderr = &output.stderr;
+        assert!(stdout.ends_with("\n  Crates needing rebuild: abi\n"));
+        assert_eq!(
+            stderr,
+            "    Binary 'abi' has undefined symbols no library provides:
+\t\tany library: bar
+    To fix: rebuild the crate against the installed versions of its libraries\n"
+        );
+    }
+
+    #[test]
+    fn report_foreign_binaries() {
+        let cargo_home = FakeCargoHome::new("foreign")
+            .registry_crate("foreign", "0.1.0", &["foreign"])
+            .registry_crate("musl", "0.1.0", &["musl"])
+            .foreign_binary("foreign")
+            .missing_interpreter_binary("musl", "/nonexistent/ld-musl-x86_64.so.1")
+            .build();
+
+        let output = cargo_home.run(&["--full"]);
+        let stdout = &output.stdout;
+        let stderr = &output.stderr;
+        assert!(stdout.ends_with("\n  Crates needing rebuild: foreign musl\n"));
+        assert!(stderr.contains(&format!(
+            "    Binary 'foreign' is built for ELF 64-bit {}, this system runs {}\n",
+            if cfg!(target_arch = "aarch64") {
+                "x86-64"
+            } else {
+                "AArch64"
+            },
+            ElfInfo::host_arch().unwrap()
+        )));
+        assert!(stderr.contains(
+            "    Binary 'musl' needs the interpreter '/nonexistent/ld-musl-x86_64.so.1', which does not exist\n"
+        ));
+        assert!(stderr.contains("    To fix: rebuild the crate on this machine\n"));
+    }
+
+    #[test]
+    fn foreign_static_binary_is_broken() {
+        let cargo_home = FakeCargoHome::new("foreign_static")
+            .registry_crate("foreign", "0.1.0", &["foreign"])
+            .foreign_static_binary("foreign")
+            .build();
+
+        let output = cargo_home.run(&["check", "--full"]);
+        assert_eq!(output.status.code(), Some(5));
+        assert!(output
+            .stdout
+            .ends_with("\n  Crates needing rebuild: foreign\n"));
+        let history =
+            fs::read_to_string(cargo_home.path().join("rebuild-check/history.jsonl")).unwrap();
+        assert!(history
+            .lines()
+            .last()
+            .unwrap()
+            .contains("\"status\":\"broken\""));
+    }
+
+    #[test]
+    fn report_verbose() {
+        let cargo_home = FakeCargoHome::new("verbose")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        let output = cargo_home.run(&["--full", "--verbose"]);
+        let stdout = &output.stdout;
+        let lines = stdout.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "  Checking crate fine 1.0.0");
+        assert!(lines[1].starts_with(&format!(
+            "    Binary 'fine': {}, dynamically linked, interpreter /",
+            ElfInfo::host_arch().unwrap()
+        )));
+    }
+
+    #[test]
+    fn report_static_binary() {
+        let cargo_home = FakeCargoHome::new("static")
+            .registry_crate("static", "0.1.0", &["static"])
+            .static_binary("static")
+            .build();
+
+        let output = cargo_home.run(&["--full", "--verbose"]);
+        assert!(output.stderr.is_empty());
+        assert_eq!(
+            output.stdout,
+            format!(
+                "  Checking crate static 0.1.0
+    Binary 'static': {}, statically linked\n
+  Everything looks good! :)\n",
+                ElfInfo::host_arch().unwrap()
+            )
+        );
+
+        // nothing to rebuild, so cargo is never run
+        let output = cargo_home.run(&["--full", "--rebuild-all", "--skip-static"]);
+        let stdout = &output.stdout;
+        assert!(stdout.ends_with("\n  Everything looks good! :)\n"));
+        let history =
+            fs::read_to_string(cargo_home.path().join("rebuild-check/history.jsonl")).unwrap();
+        assert!(history
+            .lines()
+            .last()
+            .unwrap()
+            .contains("\"status\":\"static\""));
+
+        // whether a crate is static does not depend on the flags
+        let package = decode_line(
+            "\"static 0.1.0 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"static\"]",
+        );
+        let bin_dir = cargo_home.bin_dir();
+        let report = check_crate(&package, &bin_dir, "", None, &CheckOptions::default());
+        assert!(report.static_only && !report.broken);
+        for skip_static in &[false, true] {
+            let options = CheckOptions {
+                rebuild_all: true,
+                skip_static: *skip_static,
+                ..CheckOptions::default()
+            };
+            let report = check_crate(&package, &bin_dir, "", None, &options);
+            assert!(report.static_only);
+            assert_eq!(report.broken, !skip_static);
+        }
+    }
+
+    #[test]
+    fn report_broken_scripts() {
+        let cargo_home = FakeCargoHome::new("scripts")
+            .registry_crate("scripts", "0.1.0", &["fine", "gone", "no-program"])
+            .script("fine", "#!/bin/sh\necho fine\n")
+            .script("gone", "#!/opt/rebuild-check/bin/python\n")
+            .script(
+                "no-program",
+                "#!/usr/bin/env rebuild-check-no-such-program\n",
+            )
+            .build();
+
+        let output = cargo_home.run(&["--full", "--verbose"]);
+        assert_eq!(
+            output.stdout,
+            "  Checking crate scripts 0.1.0
+    Binary 'fine': script, interpreter /bin/sh
+    Binary 'gone': script, interpreter /opt/rebuild-check/bin/python
+    Binary 'no-program': script, interpreter /usr/bin/env\n
+  Crates needing rebuild: scripts\n"
+        );
+        assert_eq!(
+            output.stderr,
+            "    Script 'gone' needs the interpreter '/opt/rebuild-check/bin/python', which does not exist
+    Script 'no-program' runs 'rebuild-check-no-such-program', which is not in PATH
+    To fix: install the interpreter the script asks for, or rebuild the crate\n"
+        );
+    }
+
+    #[test]
+    fn report_files_of_unknown_format() {
+        let cargo_home = FakeCargoHome::new("unknown_format")
+            .registry_crate("damaged", "0.1.0", &["damaged"])
+            .script("damaged", "<html>502 Bad Gateway</html>\n")
+            .build();
+
+        let output = cargo_home.run(&["check", "--full"]);
+        assert_eq!(output.status.code(), Some(5));
+        assert_eq!(
+            output.stderr,
+            "    Binary 'damaged' is neither an ELF binary nor a script
+    To fix: rebuild the crate, some of its binaries are damaged or were replaced\n"
+        );
+    }
+
+    #[test]
+    fn git_crates_rebuilt_at_installed_commit() {
+        let branch = decode_line("\"alacritty 0.1.0 (git+https://github.com/jwilm/alacritty/?branch=scrollback#9ee1cf2455d5512b087757e09451f9d122548da2)\" = [\"alacritty\"]");
+        assert_eq!(
+            git_ref_args(&branch, GitPolicy::Exact),
+            vec!["--rev", "9ee1cf2455d5512b087757e09451f9d122548da2"]
+        );
+        assert_eq!(
+            git_ref_args(&branch, GitPolicy::Follow),
+            vec!["--branch", "scrollback"]
+        );
+
+        let tag = decode_line("\"ripgrep 0.8.0 (git+https://github.com/BurntSushi/ripgrep?tag=0.8.0#23d1b91eaddbfb886a3a99d615f49551cd35cb6c)\" = [\"rg\"]");
+        assert_eq!(
+            git_ref_args(&tag, GitPolicy::Follow),
+            vec!["--tag", "0.8.0"]
+        );
+
+        // nothing pinned: the head of the default branch, unless it's the exact commit
+        let head = decode_line("\"cargo-cache 0.1.0 (git+http://github.com/matthiaskrgr/cargo-cache#6083f409343aeb8c7fcedd1877fd1ae4ef8c9e49)\" = [\"cargo-cache\"]");
+        assert!(git_ref_args(&head, GitPolicy::Follow).is_empty());
+        assert_eq!(
+            git_ref_args(&head, GitPolicy::Exact),
+            vec!["--rev", "6083f409343aeb8c7fcedd1877fd1ae4ef8c9e49"]
+        );
+    }
+
+    #[test]
+    fn git_crate_reinstall_args() {
+        let cargo_home = FakeCargoHome::new("git_reinstall")
+            .crates_toml_line("\"alacritty 0.1.0 (git+https://github.com/jwilm/alacritty/?branch=scrollback#9ee1cf2455d5512b087757e09451f9d122548da2)\" = [\"alacritty\"]")
+            .broken_binary("alacritty", "libfoo-0123456789abcdef.so")
+            // a cargo that only writes down how it was called
+            .fake_program("cargo", "echo \"$@\" >> \"$CARGO_HOME/cargo-calls\"\n")
+            .build();
+        let calls = cargo_home.path().join("cargo-calls");
+        let install_calls = || {
+            let calls = fs::read_to_string(&calls).unwrap();
+            fs::remove_file(cargo_home.path().join("cargo-calls")).unwrap();
+            calls
+                .lines()
+                .filter(|call| call.starts_with("install"))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        };
+        let root = cargo_home.path().display();
+
+        let output = cargo_home.run(&["rebuild", "--full"]);
+        assert!(output.status.success());
+        assert_eq!(
+            install_calls(),
+            vec![format!(
+                "install alacritty --force --root {} --git https://github.com/jwilm/alacritty/ \
+                 --rev 9ee1cf2455d5512b087757e09451f9d122548da2",
+                root
+            )]
+        );
+
+        let output = cargo_home.run(&["rebuild", "--full", "--git-policy", "follow"]);
+        assert!(output.status.success());
+        assert_eq!(
+            install_calls(),
+            vec![format!(
+                "install alacritty --force --root {} --git https://github.com/jwilm/alacritty/ \
+                 --branch scrollback",
+                root
+            )]
+        );
+    }
+
+    #[test]
+    fn rebuild_from_unknown_registry() {
+        let cargo_home = FakeCargoHome::new("unknown_registry")
+            .crates_toml_line("\"other 0.3.0 (registry+https://example.com/index)\" = [\"other\"]")
+            .broken_binary("other", "libfoo-0123456789abcdef.so")
+            .build();
+        // cargo install only knows registries by the name they have in the cargo config
+        let output = cargo_home.run(&["rebuild", "--locked", "--full"]);
+        assert_eq!(output.status.code(), Some(4));
+        assert!(output.stdout.ends_with(
+            "\n  Crates needing rebuild: other
+  Can't reinstall other: no registry in the cargo config has the index https://example.com/index
+    Failed rebuilds: other\n"
+        ));
+    }
+
+    #[test]
+    fn simulate_toolchain_changes() {
+        let cargo_home = FakeCargoHome::new("simulate")
+            .registry_crate("pinned", "0.1.0", &["pinned"])
+            .toolchain_binary(
+                "pinned",
+                "old-x86_64-unknown-linux-gnu",
+                "libold-0123456789abcdef.so",
+            )
+            .broken_crate("needs-new", "0.1.0", "libnew-0123456789abcdef.so")
+            .toolchain_library("new-x86_64-unknown-linux-gnu", "libnew-0123456789abcdef.so")
+            .registry_crate("fallback", "0.1.0", &["fallback"])
+            .fallback_toolchain_binary(
+                "fallback",
+                "old-x86_64-unknown-linux-gnu",
+                "libold-0123456789abcdef.so",
+            )
+            .working_crate("fine", "1.0.0")
+            .with_rustup_env()
+            .build();
+
+        // fallback finds a copy of the library once the toolchain is gone
+        let output = cargo_home.run(&["check", "--simulate-remove-toolchain", "old"]);
+        assert_eq!(output.status.code(), Some(5));
+        assert_eq!(
+            output.stdout,
+            "  Simulating the removal of toolchain 'old-x86_64-unknown-linux-gnu'
+  Checking crate pinned 0.1.0\n
+  Crates that would break: pinned\n"
+        );
+        assert!(output.stderr.starts_with(
+            "    Binary 'pinned' is missing:\n\t\tlibold-0123456789abcdef.so (rust library)\n"
+        ));
+
+        // the sysroot is in LD_LIBRARY_PATH
+        let output = cargo_home.run(&["check", "--simulate-default", "new"]);
+        assert!(output.status.success());
+        assert_eq!(
+            output.stdout,
+            "  Simulating 'new-x86_64-unknown-linux-gnu' as the default toolchain\n
+  Nothing would break.
+  Crates that would be fixed: needs-new\n"
+        );
+
+        let output = cargo_home.run(&["check", "--simulate-default", "missing"]);
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stderr, "No rustup toolchain 'missing' installed\n");
+    }
+
     #[bench]
     fn bench_decode_ldd_output_all_libs_found(b: &mut Bencher) {
         let clippy_line ="\"clippy 0.0.189 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"cargo-clippy\", \"clippy-driver\"]";
@@ -382,9 +1602,10 @@ libm.so.6 => /usr/lib/libm.so.6 (0x00007f2366d0b000)
 /lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)\n";
 
         b.iter(|| {
-            parse_ldd_output(
+            let missing_libs = parse_ldd_output(ldd_output);
+            report_missing_libs(
                 &mut to_be_printed_string,
-                ldd_output,
+                &missing_libs,
                 "clippy-driver",
                 &clippy_crateinfo,
             )
@@ -416,9 +1637,10 @@ libm.so.6 => /usr/lib/libm.so.6 (0x00007f2366d0b000)
             /lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f2367c6f000)\n";
 
         b.iter(|| {
-            parse_ldd_output(
+            let missing_libs = parse_ldd_output(ldd_output);
+            report_missing_libs(
                 &mut to_be_printed_string,
-                ldd_output,
+                &missing_libs,
                 "clippy-driver",
                 &clippy_crateinfo,
             )
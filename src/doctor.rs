@@ -0,0 +1,540 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::check_external_cmds::*;
+use crate::core::*;
+use crate::parse::*;
+
+// flags rebuilds pass to "cargo install"
+const INSTALL_FLAGS: &[&str] = &[
+    "--force",
+    "--root",
+    "--version",
+    "--git",
+    "--branch",
+    "--tag",
+    "--rev",
+    "--path",
+];
+
+// the outcome of checking one thing the tool relies on
+struct Finding {
+    description: String,
+    // how to fix it, None if there is nothing to fix
+    fix: Option<String>,
+}
+
+fn ok(description: String) -> Finding {
+    Finding {
+        description,
+        fix: None,
+    }
+}
+
+fn problem(description: String, fix: String) -> Finding {
+    Finding {
+        description,
+        fix: Some(fix),
+    }
+}
+
+// stdout of a program, None if it does not run or fails
+fn program_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .env("LANG", "en_US")
+        .env("LC_ALL", "en_US")
+        .output()
+        .ok()?;
+    if output.status.success() {
+        Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    } else {
+        None
+    }
+}
+
+fn check_programs(findings: &mut Vec<Finding>) {
+    if !has_binary("ldd") {
+        findings.push(problem(
+            String::from("ldd is missing, binaries can't be checked without it"),
+            String::from(
+                "install the tools of your C library, for glibc: libc-bin or glibc-common",
+            ),
+        ));
+    }
+    match program_output("cargo", &["install", "--help"]) {
+        Some(help) => {
+            let missing = INSTALL_FLAGS
+                .iter()
+                .filter(|flag| !help.contains(*flag))
+                .cloned()
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                findings.push(ok(String::from(
+                    "cargo install supports every flag rebuilds pass to it",
+                )));
+            } else {
+                findings.push(problem(
+                    format!("cargo install does not support {}", missing.join(" ")),
+                    String::from("update cargo, with rustup: rustup update"),
+                ));
+            }
+        }
+        None => findings.push(problem(
+            String::from("cargo does not run, crates can't be rebuilt"),
+            String::from("install cargo, or add the directory it is in to PATH"),
+        )),
+    }
+}
+
+fn in_path(dir: &Path) -> bool {
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+    env::var_os("PATH").map_or(false, |path| {
+        env::split_paths(&path).any(|entry| fs::canonicalize(&entry).unwrap_or(entry) == dir)
+    })
+}
+
+fn check_cargo(findings: &mut Vec<Finding>) {
+    match get_cargo_home() {
+        Ok(ref cargo_home) if cargo_home.is_dir() => {
+            findings.push(ok(format!("$CARGO_HOME is '{}'", cargo_home.display())))
+        }
+        Ok(cargo_home) => findings.push(problem(
+            format!("$CARGO_HOME '{}' does not exist", cargo_home.display()),
+            String::from("set CARGO_HOME to the directory cargo installs crates into"),
+        )),
+        Err(_) => findings.push(problem(
+            String::from("$CARGO_HOME could not be determined"),
+            String::from("fix the cargo configuration errors above, or set CARGO_HOME"),
+        )),
+    }
+
+    for install_root in get_install_roots(Vec::new()).unwrap_or_default() {
+        let crates_toml = install_root.join(".crates.toml");
+        match fs::read_to_string(&crates_toml) {
+            Ok(content) => {
+                let version = content.lines().next().unwrap_or("").trim().to_string();
+                if version == "[v1]" {
+                    findings.push(ok(format!(
+                        "'{}' has format version v1",
+                        crates_toml.display()
+                    )));
+                } else {
+                    findings.push(problem(
+                        format!(
+                            "'{}' has the unknown format version '{}'",
+                            crates_toml.display(),
+                            version
+                        ),
+                        String::from("update cargo-rebuild-check, this version only reads v1"),
+                    ));
+                }
+            }
+            Err(_) => findings.push(ok(format!(
+                "nothing installed into '{}' yet",
+                install_root.display()
+            ))),
+        }
+
+        let bin_dir = install_root.join("bin");
+        if in_path(&bin_dir) {
+            findings.push(ok(format!("'{}' is in PATH", bin_dir.display())));
+        } else {
+            findings.push(problem(
+                format!(
+                    "'{}' is not in PATH, installed binaries can't be run by name",
+                    bin_dir.display()
+                ),
+                format!(
+                    "add 'export PATH=\"{}:$PATH\"' to your shell profile",
+                    bin_dir.display()
+                ),
+            ));
+        }
+    }
+}
+
+fn check_rustc(findings: &mut Vec<Finding>) {
+    let rustc = get_rustc();
+    let from_env = env::var_os("RUSTC").is_some();
+    match program_output(&rustc, &["--version"]) {
+        Some(version) => findings.push(ok(format!(
+            "{} is {}{}",
+            rustc,
+            version,
+            if from_env { " (from $RUSTC)" } else { "" }
+        ))),
+        None => {
+            findings.push(problem(
+                format!("'{}' does not run", rustc),
+                if from_env {
+                    String::from("point RUSTC to a working rustc, or unset it")
+                } else {
+                    String::from("install rust, for example with rustup from https://rustup.rs")
+                },
+            ));
+            return;
+        }
+    }
+    // the libraries the check adds to LD_LIBRARY_PATH
+    match program_output(&rustc, &["--print", "sysroot"]) {
+        Some(ref sysroot) if Path::new(sysroot).join("lib").is_dir() => {
+            findings.push(ok(format!("the rustc sysroot is '{}'", sysroot)))
+        }
+        Some(sysroot) => findings.push(problem(
+            format!("the rustc sysroot '{}' has no lib dir", sysroot),
+            String::from("reinstall the toolchain rustc belongs to"),
+        )),
+        None => findings.push(problem(
+            format!("'{} --print sysroot' fails", rustc),
+            String::from("reinstall the toolchain rustc belongs to"),
+        )),
+    }
+    // rebuilds link against the libraries of cargo's rustc, the check looks at this one's
+    if let (Some(cargo), Some(rustc)) = (cargo_version(), rustc_version()) {
+        if cargo.same_release(rustc) {
+            findings.push(ok(format!(
+                "cargo {} and rustc {} are from the same release",
+                cargo, rustc
+            )))
+        } else {
+            findings.push(problem(
+                format!(
+                    "cargo {} and rustc {} are from different releases",
+                    cargo, rustc
+                ),
+                String::from("use cargo and rustc of the same toolchain"),
+            ))
+        }
+    }
+}
+
+fn check_rustup(findings: &mut Vec<Finding>) {
+    match program_output("rustup", &["--version"]) {
+        Some(version) => findings.push(ok(version.lines().next().unwrap_or("rustup").to_string())),
+        None => {
+            findings.push(ok(String::from(
+                "rustup is not installed, there are no other toolchains to look at",
+            )));
+            return;
+        }
+    }
+    // the rustup proxy sets RUSTUP_TOOLCHAIN for everything it runs, it is only a problem if
+    // the libraries of another toolchain than the default one would be checked against
+    if let Ok(toolchain) = env::var("RUSTUP_TOOLCHAIN") {
+        let default = program_output("rustup", &["default"])
+            .and_then(|default| default.split_whitespace().next().map(String::from));
+        let is_default = match default {
+            Some(ref default) => {
+                toolchain == *default
+                    || match (find_toolchain(&toolchain), find_toolchain(default)) {
+                        (Some(toolchain), Some(default)) => toolchain == default,
+                        _ => false,
+                    }
+            }
+            None => false,
+        };
+        if is_default {
+            findings.push(ok(format!(
+                "$RUSTUP_TOOLCHAIN={} is the default toolchain",
+                toolchain
+            )));
+        } else {
+            findings.push(problem(
+                format!(
+                    "$RUSTUP_TOOLCHAIN={} overrides the default toolchain {}",
+                    toolchain,
+                    default.unwrap_or_else(|| String::from("(none)"))
+                ),
+                String::from(
+                    "run cargo rebuild-check outside of directories with a rust-toolchain file \
+                     or a rustup override, and unset RUSTUP_TOOLCHAIN, unless the crates were \
+                     built with it",
+                ),
+            ));
+        }
+    } else if let Some(active) = program_output("rustup", &["show", "active-toolchain"]) {
+        if active.contains("override") {
+            findings.push(problem(
+                format!("the toolchain is overridden here: {}", active),
+                String::from(
+                    "run cargo rebuild-check outside of directories with a rust-toolchain file \
+                     or a rustup override",
+                ),
+            ));
+        }
+    }
+}
+
+// "include" patterns like "/etc/ld.so.conf.d/*.conf", only a '*' in the file name is supported
+fn expand_include(pattern: &Path) -> Vec<PathBuf> {
+    let name = pattern
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = pattern.parent().unwrap_or_else(|| Path::new("/"));
+    if !name.contains('*') {
+        return vec![pattern.to_path_buf()];
+    }
+    let mut parts = name.splitn(2, '*');
+    let (prefix, suffix) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut files = fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    let name = path
+                        .file_name()
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .unwrap_or_default();
+                    name.len() >= prefix.len() + suffix.len()
+                        && name.starts_with(prefix)
+                        && name.ends_with(suffix)
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+// the dirs listed in a ld.so.conf and the files they come from, following includes
+fn read_ld_so_conf(
+    conf: &Path,
+    files: &mut Vec<PathBuf>,
+    dirs: &mut Vec<(PathBuf, PathBuf)>,
+    seen: &mut HashSet<PathBuf>,
+) {
+    if !seen.insert(conf.to_path_buf()) {
+        return;
+    }
+    let content = match fs::read_to_string(conf) {
+        Ok(content) => content,
+        Err(_) => return,
+    };
+    files.push(conf.to_path_buf());
+    let conf_dir = conf.parent().unwrap_or_else(|| Path::new("/"));
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.starts_with("include") && line[7..].starts_with(char::is_whitespace) {
+            for pattern in line[7..].split_whitespace() {
+                for include in expand_include(&conf_dir.join(pattern)) {
+                    read_ld_so_conf(&include, files, dirs, seen);
+                }
+            }
+        } else if !line.is_empty() && !line.starts_with("hwcap") {
+            let listed = line
+                .split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| (PathBuf::from(dir), conf.to_path_buf()));
+            dirs.extend(listed);
+        }
+    }
+}
+
+fn check_ld_so_conf(conf: &Path, cache: &Path, findings: &mut Vec<Finding>) {
+    if !conf.is_file() {
+        findings.push(ok(format!(
+            "there is no '{}', libraries are only searched in the default dirs",
+            conf.display()
+        )));
+        return;
+    }
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    read_ld_so_conf(conf, &mut files, &mut dirs, &mut HashSet::new());
+
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let cache_modified = match modified(cache) {
+        Ok(cache_modified) => cache_modified,
+        Err(_) => {
+            findings.push(problem(
+                format!(
+                    "there is no '{}', the dirs in '{}' are not searched",
+                    cache.display(),
+                    conf.display()
+                ),
+                String::from("run 'sudo ldconfig'"),
+            ));
+            return;
+        }
+    };
+    // listing dirs that don't exist is harmless, distributions do it all the time
+    let changed = files
+        .iter()
+        .chain(dirs.iter().map(|(dir, _)| dir))
+        .filter(|path| modified(path).map_or(false, |time| time > cache_modified))
+        .map(|path| format!("'{}'", path.display()))
+        .collect::<Vec<_>>();
+    if changed.is_empty() {
+        findings.push(ok(format!(
+            "'{}' lists {} dirs, '{}' is up to date",
+            conf.display(),
+            dirs.len(),
+            cache.display()
+        )));
+    } else {
+        findings.push(problem(
+            format!(
+                "{} changed after '{}' was built, new libraries are not found",
+                changed.join(", "),
+                cache.display()
+            ),
+            String::from("run 'sudo ldconfig'"),
+        ));
+    }
+}
+
+fn print_findings(findings: &[Finding]) -> usize {
+    let mut problems = 0;
+    for finding in findings {
+        match finding.fix {
+            None => println!("  ok       {}", finding.description),
+            Some(ref fix) => {
+                println!("  problem  {}", finding.description);
+                println!("           fix: {}", fix);
+                problems += 1;
+            }
+        }
+    }
+    problems
+}
+
+// check everything the tool relies on, returns the number of problems found
+pub(crate) fn diagnose() -> usize {
+    let mut findings = Vec::new();
+    check_programs(&mut findings);
+    check_cargo(&mut findings);
+    check_rustc(&mut findings);
+    check_rustup(&mut findings);
+    check_ld_so_conf(
+        Path::new("/etc/ld.so.conf"),
+        Path::new("/etc/ld.so.cache"),
+        &mut findings,
+    );
+    let problems = print_findings(&findings);
+    match problems {
+        0 => println!("\n  No problems found"),
+        1 => println!("\n  Found 1 problem"),
+        _ => println!("\n  Found {} problems", problems),
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn doctor_finds_environment_problems() {
+        let toolchain = "default-x86_64-unknown-linux-gnu";
+        let cargo_home = FakeCargoHome::new("doctor")
+            .working_crate("fine", "1.0.0")
+            .fake_rustup(toolchain, &[])
+            .build();
+        // what the rustup proxy sets when run as "cargo rebuild-check doctor"
+        let rustc = toolchain_rustc();
+        let envs = [("RUSTC", rustc.as_str()), ("RUSTUP_TOOLCHAIN", toolchain)];
+
+        let output = cargo_home.run_with_env(&["doctor"], &envs);
+        assert_eq!(output.status.code(), Some(3));
+        let stdout = &output.stdout;
+        let path = cargo_home.path().display();
+        assert!(stdout.contains(&format!("  ok       $CARGO_HOME is '{}'\n", path)));
+        assert!(stdout.contains(&format!(
+            "  ok       '{}/.crates.toml' has format version v1\n",
+            path
+        )));
+        assert!(stdout.contains(&format!(
+            "  problem  '{0}/bin' is not in PATH, installed binaries can't be run by name
+           fix: add 'export PATH=\"{0}/bin:$PATH\"' to your shell profile\n",
+            path
+        )));
+        assert!(stdout.contains(&format!("  ok       {} is rustc 1.", rustc)));
+        assert!(stdout.contains("  ok       cargo 1."));
+        assert!(!stdout.contains("are from different releases"));
+        assert!(stdout.contains("  ok       rustup 1.13.0 (ea9259c1b 2018-07-16)\n"));
+        assert!(stdout.contains(&format!(
+            "  ok       $RUSTUP_TOOLCHAIN={} is the default toolchain\n",
+            toolchain
+        )));
+        assert!(
+            stdout.contains("  ok       cargo install supports every flag rebuilds pass to it\n")
+        );
+    }
+
+    #[test]
+    fn ld_so_conf_sanity() {
+        let cargo_home = FakeCargoHome::new("ld_so_conf").build();
+        let etc = cargo_home.path().join("etc");
+        fs::create_dir_all(etc.join("ld.so.conf.d")).unwrap();
+        let conf = etc.join("ld.so.conf");
+        let cache = etc.join("ld.so.cache");
+        let lib_dir = cargo_home.path().join("build");
+        let local = etc.join("ld.so.conf.d/local.conf");
+        fs::write(&cache, "").unwrap();
+        fs::write(&conf, "# comment\ninclude ld.so.conf.d/*.conf\n").unwrap();
+        fs::write(&local, format!("{}\n", lib_dir.display())).unwrap();
+        fs::write(etc.join("ld.so.conf.d/ignored.txt"), "/does/not/matter\n").unwrap();
+        // files written right after each other may get the same time
+        let set_modified = |path: &Path, time: &str| {
+            assert!(Command::new("touch")
+                .arg("-d")
+                .arg(time)
+                .arg(path)
+                .status()
+                .unwrap()
+                .success());
+        };
+
+        // the configuration files are newer than the cache
+        set_modified(&cache, "@1000000000");
+        let mut findings = Vec::new();
+        check_ld_so_conf(&conf, &cache, &mut findings);
+        assert_eq!(findings.len(), 1);
+        assert_eq!(
+            findings[0].description,
+            format!(
+                "'{}', '{}', '{}' changed after '{}' was built, new libraries are not found",
+                conf.display(),
+                local.display(),
+                lib_dir.display(),
+                cache.display()
+            )
+        );
+        assert_eq!(findings[0].fix, Some(String::from("run 'sudo ldconfig'")));
+
+        for path in &[&conf, &local, &lib_dir] {
+            set_modified(path, "@946684800");
+        }
+        let mut findings = Vec::new();
+        check_ld_so_conf(&conf, &cache, &mut findings);
+        assert_eq!(
+            findings[0].description,
+            format!(
+                "'{}' lists 1 dirs, '{}' is up to date",
+                conf.display(),
+                cache.display()
+            )
+        );
+        assert_eq!(findings[0].fix, None);
+
+        // a library installed without running ldconfig
+        fs::write(lib_dir.join("libnew.so"), "").unwrap();
+        let mut findings = Vec::new();
+        check_ld_so_conf(&conf, &cache, &mut findings);
+        assert_eq!(
+            findings[0].description,
+            format!(
+                "'{}' changed after '{}' was built, new libraries are not found",
+                lib_dir.display(),
+                cache.display()
+            )
+        );
+    }
+}
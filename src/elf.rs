@@ -0,0 +1,1048 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+use crate::errors::*;
+
+// segment types
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+// segment flags
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+// machines, see /usr/include/elf.h
+const EM_386: u16 = 3;
+const EM_MIPS: u16 = 8;
+const EM_PPC: u16 = 20;
+const EM_PPC64: u16 = 21;
+const EM_S390: u16 = 22;
+const EM_ARM: u16 = 40;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+// section types, see elf(5)
+const SHT_DYNAMIC: u32 = 6;
+const SHT_NOBITS: u32 = 8;
+const SHT_DYNSYM: u32 = 11;
+const SHT_GNU_VERDEF: u32 = 0x6fff_fffd;
+const SHT_GNU_VERNEED: u32 = 0x6fff_fffe;
+const SHT_GNU_VERSYM: u32 = 0x6fff_ffff;
+// symbol bindings
+const STB_GLOBAL: u8 = 1;
+const STB_WEAK: u8 = 2;
+const STB_GNU_UNIQUE: u8 = 10;
+// symbol visibility
+const STV_DEFAULT: u8 = 0;
+const STV_PROTECTED: u8 = 3;
+// section index of undefined symbols
+const SHN_UNDEF: u16 = 0;
+
+// dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+// the version definition naming the library itself, not an actual version
+const VER_FLG_BASE: u16 = 0x1;
+// a needed version the loader does without, the symbols using it are weak
+const VER_FLG_WEAK: u16 = 0x2;
+
+// what kind of machine a binary is built for and how it gets loaded
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ElfInfo {
+    pub(crate) is_64bit: bool,
+    pub(crate) machine: u16,
+    // PT_INTERP, None for static binaries
+    pub(crate) interpreter: Option<String>,
+}
+
+// the class and machine of the binaries this system runs natively
+fn native_arch() -> Option<(bool, u16)> {
+    if cfg!(target_arch = "x86_64") {
+        Some((true, EM_X86_64))
+    } else if cfg!(target_arch = "x86") {
+        Some((false, EM_386))
+    } else if cfg!(target_arch = "aarch64") {
+        Some((true, EM_AARCH64))
+    } else if cfg!(target_arch = "arm") {
+        Some((false, EM_ARM))
+    } else if cfg!(target_arch = "powerpc64") {
+        Some((true, EM_PPC64))
+    } else if cfg!(target_arch = "powerpc") {
+        Some((false, EM_PPC))
+    } else if cfg!(target_arch = "s390x") {
+        Some((true, EM_S390))
+    } else if cfg!(target_arch = "riscv64") {
+        Some((true, EM_RISCV))
+    } else {
+        None
+    }
+}
+
+// the 32-bit class and machine a 64-bit host can run as well
+fn compat_arch(host: (bool, u16)) -> Option<(bool, u16)> {
+    match host {
+        (true, EM_X86_64) => Some((false, EM_386)),
+        (true, EM_AARCH64) => Some((false, EM_ARM)),
+        (true, EM_PPC64) => Some((false, EM_PPC)),
+        _ => None,
+    }
+}
+
+fn describe_arch(is_64bit: bool, machine: u16) -> String {
+    let machine = match machine {
+        EM_386 => String::from("x86"),
+        EM_MIPS => String::from("MIPS"),
+        EM_PPC => String::from("PowerPC"),
+        EM_PPC64 => String::from("PowerPC64"),
+        EM_S390 => String::from("S/390"),
+        EM_ARM => String::from("ARM"),
+        EM_X86_64 => String::from("x86-64"),
+        EM_AARCH64 => String::from("AArch64"),
+        EM_RISCV => String::from("RISC-V"),
+        other => format!("machine {}", other),
+    };
+    format!("ELF {}-bit {}", if is_64bit { 64 } else { 32 }, machine)
+}
+
+impl ElfInfo {
+    pub(crate) fn arch(&self) -> String {
+        describe_arch(self.is_64bit, self.machine)
+    }
+
+    // "ELF 64-bit x86-64", None if we don't know what we are running on
+    pub(crate) fn host_arch() -> Option<String> {
+        native_arch().map(|(is_64bit, machine)| describe_arch(is_64bit, machine))
+    }
+
+    // built for a different kind of machine than the one we run on, 32-bit binaries run
+    // natively too as long as the multilib loader they ask for is installed
+    pub(crate) fn is_foreign(&self) -> bool {
+        let host = match native_arch() {
+            Some(host) => host,
+            None => return false,
+        };
+        if host == (self.is_64bit, self.machine) {
+            false
+        } else if compat_arch(host) == Some((self.is_64bit, self.machine)) {
+            self.missing_interpreter().is_some()
+        } else {
+            true
+        }
+    }
+
+    // loaded without the help of the dynamic linker, this includes static-pie binaries
+    pub(crate) fn is_static(&self) -> bool {
+        self.interpreter.is_none()
+    }
+
+    // the interpreter the binary asks for, if it does not exist
+    pub(crate) fn missing_interpreter(&self) -> Option<&str> {
+        match self.interpreter {
+            Some(ref interpreter) if !Path::new(interpreter).exists() => Some(interpreter),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ElfInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.interpreter {
+            Some(ref interpreter) => write!(
+                f,
+                "{}, dynamically linked, interpreter {}",
+                self.arch(),
+                interpreter
+            ),
+            None => write!(f, "{}, statically linked", self.arch()),
+        }
+    }
+}
+
+// what the dynamic loader needs to know to find the libraries of a binary, from .dynamic
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct DynamicInfo {
+    // DT_NEEDED, in the order the loader goes through them
+    pub(crate) needed: Vec<String>,
+    // DT_RPATH and DT_RUNPATH, split at ':' but with $ORIGIN still in them
+    pub(crate) rpath: Vec<String>,
+    pub(crate) runpath: Vec<String>,
+}
+
+// an entry of .dynsym
+struct Symbol {
+    name: String,
+    binding: u8,
+    visibility: u8,
+    section_index: u16,
+}
+
+#[derive(Clone)]
+struct Section {
+    // where the section header itself is, to update it
+    header: usize,
+    kind: u32,
+    offset: usize,
+    size: usize,
+    link: usize,
+    info: usize,
+}
+
+#[derive(Clone, Copy)]
+struct ProgramHeader {
+    // where the program header itself is, to update it
+    header: usize,
+    kind: u32,
+    flags: u32,
+    offset: usize,
+    vaddr: usize,
+    filesz: usize,
+    memsz: usize,
+    align: usize,
+}
+
+// only looks at the magic number, to tell binaries from files that ended up in bin/ otherwise
+pub(crate) fn is_elf_file(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok()
+        && &magic == b"\x7fELF"
+}
+
+// an ELF file read into memory, only what we need to check links is parsed
+pub(crate) struct Elf {
+    data: Vec<u8>,
+    is_64bit: bool,
+    little_endian: bool,
+    sections: Vec<Section>,
+}
+
+impl Elf {
+    pub(crate) fn read(path: &Path) -> Result<Self, ErrorKind> {
+        let data = fs::read(path).map_err(|_| ErrorKind::NotElf)?;
+        Self::parse(data)
+    }
+
+    fn parse(data: Vec<u8>) -> Result<Self, ErrorKind> {
+        if data.len() < 0x34 || &data[..4] != b"\x7fELF" {
+            return Err(ErrorKind::NotElf);
+        }
+        let is_64bit = match data[4] {
+            1 => false,
+            2 => true,
+            _ => return Err(ErrorKind::MalformedElf),
+        };
+        let little_endian = match data[5] {
+            1 => true,
+            2 => false,
+            _ => return Err(ErrorKind::MalformedElf),
+        };
+        let mut elf = Self {
+            data,
+            is_64bit,
+            little_endian,
+            sections: Vec::new(),
+        };
+        elf.sections = elf.read_sections()?;
+        Ok(elf)
+    }
+
+    fn uint(&self, offset: usize, size: usize) -> Result<u64, ErrorKind> {
+        let end = offset.checked_add(size).ok_or(ErrorKind::MalformedElf)?;
+        let bytes = self.data.get(offset..end).ok_or(ErrorKind::MalformedElf)?;
+        let mut value = 0;
+        for index in 0..size {
+            let byte = if self.little_endian {
+                bytes[size - 1 - index]
+            } else {
+                bytes[index]
+            };
+            value = (value << 8) | u64::from(byte);
+        }
+        Ok(value)
+    }
+
+    fn u16(&self, offset: usize) -> Result<u16, ErrorKind> {
+        self.uint(offset, 2).map(|value| value as u16)
+    }
+
+    fn u32(&self, offset: usize) -> Result<u32, ErrorKind> {
+        self.uint(offset, 4).map(|value| value as u32)
+    }
+
+    // a 32 or 64 bit value, depending on the class of the file
+    fn word(&self, offset: usize) -> Result<usize, ErrorKind> {
+        self.uint(offset, if self.is_64bit { 8 } else { 4 })
+            .map(|value| value as usize)
+    }
+
+    fn read_sections(&self) -> Result<Vec<Section>, ErrorKind> {
+        let (shoff, shentsize, shnum) = if self.is_64bit {
+            (self.word(0x28)?, self.u16(0x3a)?, self.u16(0x3c)?)
+        } else {
+            (self.word(0x20)?, self.u16(0x2e)?, self.u16(0x30)?)
+        };
+        // the section header table has to be within the file
+        let min_entsize = if self.is_64bit { 0x40 } else { 0x28 };
+        let in_file = shoff
+            .checked_add(usize::from(shnum) * usize::from(shentsize))
+            .map(|end| end <= self.data.len());
+        if shnum > 0 && (shentsize < min_entsize || in_file != Some(true)) {
+            return Err(ErrorKind::MalformedElf);
+        }
+        let mut sections = Vec::with_capacity(usize::from(shnum));
+        for index in 0..usize::from(shnum) {
+            let header = shoff + index * usize::from(shentsize);
+            let section = if self.is_64bit {
+                Section {
+                    header,
+                    kind: self.u32(header + 4)?,
+                    offset: self.word(header + 24)?,
+                    size: self.word(header + 32)?,
+                    link: self.u32(header + 40)? as usize,
+                    info: self.u32(header + 44)? as usize,
+                }
+            } else {
+                Section {
+                    header,
+                    kind: self.u32(header + 4)?,
+                    offset: self.word(header + 16)?,
+                    size: self.word(header + 20)?,
+                    link: self.u32(header + 24)? as usize,
+                    info: self.u32(header + 28)? as usize,
+                }
+            };
+            // everything we read later on lies within a section, so make sure they do exist
+            let in_file = section
+                .offset
+                .checked_add(section.size)
+                .map(|end| end <= self.data.len());
+            if section.kind != SHT_NOBITS && in_file != Some(true) {
+                return Err(ErrorKind::MalformedElf);
+            }
+            sections.push(section);
+        }
+        Ok(sections)
+    }
+
+    // offset, entry size and number of entries of the program header table
+    fn program_header_table(&self) -> Result<(usize, usize, usize), ErrorKind> {
+        let (phoff, phentsize, phnum) = if self.is_64bit {
+            (self.word(0x20)?, self.u16(0x36)?, self.u16(0x38)?)
+        } else {
+            (self.word(0x1c)?, self.u16(0x2a)?, self.u16(0x2c)?)
+        };
+        Ok((phoff, usize::from(phentsize), usize::from(phnum)))
+    }
+
+    fn program_headers(&self) -> Result<Vec<ProgramHeader>, ErrorKind> {
+        let (phoff, phentsize, phnum) = self.program_header_table()?;
+        let mut headers = Vec::with_capacity(phnum);
+        for index in 0..phnum {
+            let header = phoff
+                .checked_add(index * phentsize)
+                .ok_or(ErrorKind::MalformedElf)?;
+            // the flags moved to the front in the 64 bit layout
+            headers.push(if self.is_64bit {
+                ProgramHeader {
+                    header,
+                    kind: self.u32(header)?,
+                    flags: self.u32(header + 4)?,
+                    offset: self.word(header + 8)?,
+                    vaddr: self.word(header + 16)?,
+                    filesz: self.word(header + 32)?,
+                    memsz: self.word(header + 40)?,
+                    align: self.word(header + 48)?,
+                }
+            } else {
+                ProgramHeader {
+                    header,
+                    kind: self.u32(header)?,
+                    offset: self.word(header + 4)?,
+                    vaddr: self.word(header + 8)?,
+                    filesz: self.word(header + 16)?,
+                    memsz: self.word(header + 20)?,
+                    flags: self.u32(header + 24)?,
+                    align: self.word(header + 28)?,
+                }
+            });
+        }
+        Ok(headers)
+    }
+
+    // class, machine and interpreter from the ELF header and the program headers
+    pub(crate) fn info(&self) -> Result<ElfInfo, ErrorKind> {
+        let mut interpreter = None;
+        for segment in self.program_headers()? {
+            if segment.kind != PT_INTERP {
+                continue;
+            }
+            let end = segment
+                .offset
+                .checked_add(segment.filesz)
+                .ok_or(ErrorKind::MalformedElf)?;
+            let path = self
+                .data
+                .get(segment.offset..end)
+                .ok_or(ErrorKind::MalformedElf)?;
+            // NUL terminated
+            let length = path
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(segment.filesz);
+            interpreter = Some(String::from_utf8_lossy(&path[..length]).into_owned());
+        }
+        Ok(ElfInfo {
+            is_64bit: self.is_64bit,
+            machine: self.u16(0x12)?,
+            interpreter,
+        })
+    }
+
+    fn section(&self, kind: u32) -> Option<&Section> {
+        self.sections.iter().find(|section| section.kind == kind)
+    }
+
+    // a NUL terminated string from the string table section with the given index
+    fn string(&self, string_table: usize, offset: usize) -> Result<String, ErrorKind> {
+        let table = self
+            .sections
+            .get(string_table)
+            .ok_or(ErrorKind::MalformedElf)?;
+        let end = table
+            .offset
+            .checked_add(table.size)
+            .ok_or(ErrorKind::MalformedElf)?;
+        let table_data = self
+            .data
+            .get(table.offset..end)
+            .ok_or(ErrorKind::MalformedElf)?;
+        let bytes = table_data.get(offset..).ok_or(ErrorKind::MalformedElf)?;
+        let length = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(ErrorKind::MalformedElf)?;
+        Ok(String::from_utf8_lossy(&bytes[..length]).into_owned())
+    }
+
+    // (file, version, index, weak) for every version needed from other libraries
+    fn needed_versions(&self) -> Result<Vec<(String, String, u16, bool)>, ErrorKind> {
+        let section = match self.section(SHT_GNU_VERNEED) {
+            Some(section) => section,
+            None => return Ok(Vec::new()),
+        };
+        let mut needed = Vec::new();
+        let mut entry = section.offset;
+        // sh_info holds the number of entries
+        for _ in 0..section.info {
+            // Elf_Verneed: vn_version, vn_cnt, vn_file, vn_aux, vn_next
+            let count = self.u16(entry + 2)?;
+            let file = self.string(section.link, self.u32(entry + 4)? as usize)?;
+            let mut aux = entry + self.u32(entry + 8)? as usize;
+            for _ in 0..count {
+                // Elf_Vernaux: vna_hash, vna_flags, vna_other, vna_name, vna_next
+                let weak = self.u16(aux + 4)? & VER_FLG_WEAK != 0;
+                let index = self.u16(aux + 6)?;
+                let version = self.string(section.link, self.u32(aux + 8)? as usize)?;
+                needed.push((file.clone(), version, index, weak));
+                aux += self.u32(aux + 12)? as usize;
+            }
+
+            let next = self.u32(entry + 12)? as usize;
+            if next == 0 {
+                break;
+            }
+            entry += next;
+        }
+        Ok(needed)
+    }
+
+    // the symbol versions needed from other libraries, from .gnu.version_r, without the weak
+    // ones: [("libc.so.6", ["GLIBC_2.2.5", "GLIBC_2.28"])]
+    pub(crate) fn version_requirements(&self) -> Result<Vec<(String, Vec<String>)>, ErrorKind> {
+        let mut requirements: Vec<(String, Vec<String>)> = Vec::new();
+        for (file, version, _, weak) in self.needed_versions()? {
+            if weak {
+                continue;
+            }
+            match requirements.iter().position(|(known, _)| *known == file) {
+                Some(position) => requirements[position].1.push(version),
+                None => requirements.push((file, vec![version])),
+            }
+        }
+        Ok(requirements)
+    }
+
+    fn dynamic_symbols(&self) -> Result<Vec<Symbol>, ErrorKind> {
+        let section = match self.section(SHT_DYNSYM) {
+            Some(section) => section,
+            None => return Ok(Vec::new()),
+        };
+        let entry_size = if self.is_64bit { 24 } else { 16 };
+        let mut symbols = Vec::with_capacity(section.size / entry_size);
+        for index in 0..section.size / entry_size {
+            let entry = section.offset + index * entry_size;
+            // Elf64_Sym: st_name, st_info, st_other, st_shndx, st_value, st_size
+            // Elf32_Sym: st_name, st_value, st_size, st_info, st_other, st_shndx
+            let (info, other, section_index) = if self.is_64bit {
+                (
+                    self.data[entry + 4],
+                    self.data[entry + 5],
+                    self.u16(entry + 6)?,
+                )
+            } else {
+                (
+                    self.data[entry + 12],
+                    self.data[entry + 13],
+                    self.u16(entry + 14)?,
+                )
+            };
+            symbols.push(Symbol {
+                name: self.string(section.link, self.u32(entry)? as usize)?,
+                binding: info >> 4,
+                visibility: other & 0x3,
+                section_index,
+            });
+        }
+        Ok(symbols)
+    }
+
+    // symbols other libraries have to provide, with the library the symbol version
+    // says it comes from: [("git_libgit2_init", None), ("memcpy", Some("libc.so.6"))]
+    pub(crate) fn undefined_symbols(&self) -> Result<Vec<(String, Option<String>)>, ErrorKind> {
+        let needed = self.needed_versions()?;
+        let versions = self.section(SHT_GNU_VERSYM);
+        let mut undefined = Vec::new();
+        for (index, symbol) in self.dynamic_symbols()?.into_iter().enumerate() {
+            // weak symbols may stay undefined
+            if symbol.section_index != SHN_UNDEF
+                || symbol.binding != STB_GLOBAL
+                || symbol.name.is_empty()
+            {
+                continue;
+            }
+            // .gnu.version has one entry per symbol, the top bit marks hidden versions
+            let library = match versions {
+                Some(versions) => {
+                    let version_index = self.u16(versions.offset + index * 2)? & 0x7fff;
+                    needed
+                        .iter()
+                        .find(|(_, _, needed_index, _)| *needed_index == version_index)
+                        .map(|(file, _, _, _)| file.clone())
+                }
+                None => None,
+            };
+            undefined.push((symbol.name, library));
+        }
+        Ok(undefined)
+    }
+
+    // symbols this library provides to others
+    pub(crate) fn exported_symbols(&self) -> Result<Vec<String>, ErrorKind> {
+        Ok(self
+            .dynamic_symbols()?
+            .into_iter()
+            .filter(|symbol| {
+                symbol.section_index != SHN_UNDEF
+                    && (symbol.binding == STB_GLOBAL
+                        || symbol.binding == STB_WEAK
+                        || symbol.binding == STB_GNU_UNIQUE)
+                    && (symbol.visibility == STV_DEFAULT || symbol.visibility == STV_PROTECTED)
+            })
+            .map(|symbol| symbol.name)
+            .collect())
+    }
+
+    // (tag, value) of the entries of .dynamic, up to DT_NULL
+    fn dynamic_entries(&self, section: &Section) -> Result<Vec<(u64, usize)>, ErrorKind> {
+        let entry_size = if self.is_64bit { 16 } else { 8 };
+        let word_size = entry_size / 2;
+        let mut entries = Vec::new();
+        for index in 0..section.size / entry_size {
+            // Elf_Dyn: d_tag, d_val
+            let entry = section.offset + index * entry_size;
+            let tag = self.uint(entry, word_size)?;
+            if tag == DT_NULL {
+                break;
+            }
+            entries.push((tag, self.word(entry + word_size)?));
+        }
+        Ok(entries)
+    }
+
+    pub(crate) fn dynamic(&self) -> Result<DynamicInfo, ErrorKind> {
+        let section = match self.section(SHT_DYNAMIC) {
+            Some(section) => section,
+            None => return Ok(DynamicInfo::default()),
+        };
+        let mut dynamic = DynamicInfo::default();
+        for (tag, value) in self.dynamic_entries(section)? {
+            match tag {
+                DT_NEEDED => dynamic.needed.push(self.string(section.link, value)?),
+                DT_RPATH => dynamic.rpath.extend(
+                    self.string(section.link, value)?
+                        .split(':')
+                        .map(String::from),
+                ),
+                DT_RUNPATH => dynamic.runpath.extend(
+                    self.string(section.link, value)?
+                        .split(':')
+                        .map(String::from),
+                ),
+                _ => {}
+            }
+        }
+        Ok(dynamic)
+    }
+
+    // the file as it is now, with the changes made to it
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn set_uint(&mut self, offset: usize, size: usize, value: u64) -> Result<(), ErrorKind> {
+        let end = offset.checked_add(size).ok_or(ErrorKind::MalformedElf)?;
+        let little_endian = self.little_endian;
+        let bytes = self
+            .data
+            .get_mut(offset..end)
+            .ok_or(ErrorKind::MalformedElf)?;
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            let shift = if little_endian {
+                index
+            } else {
+                size - 1 - index
+            };
+            *byte = (value >> (shift * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    fn set_word(&mut self, offset: usize, value: usize) -> Result<(), ErrorKind> {
+        let size = if self.is_64bit { 8 } else { 4 };
+        self.set_uint(offset, size, value as u64)
+    }
+
+    fn set_program_header(&mut self, segment: &ProgramHeader) -> Result<(), ErrorKind> {
+        let header = segment.header;
+        self.set_uint(header, 4, u64::from(segment.kind))?;
+        // p_paddr is not used on linux, linkers set it to p_vaddr
+        if self.is_64bit {
+            self.set_uint(header + 4, 4, u64::from(segment.flags))?;
+            self.set_word(header + 8, segment.offset)?;
+            self.set_word(header + 16, segment.vaddr)?;
+            self.set_word(header + 24, segment.vaddr)?;
+            self.set_word(header + 32, segment.filesz)?;
+            self.set_word(header + 40, segment.memsz)?;
+            self.set_word(header + 48, segment.align)
+        } else {
+            self.set_word(header + 4, segment.offset)?;
+            self.set_word(header + 8, segment.vaddr)?;
+            self.set_word(header + 12, segment.vaddr)?;
+            self.set_word(header + 16, segment.filesz)?;
+            self.set_word(header + 20, segment.memsz)?;
+            self.set_uint(header + 24, 4, u64::from(segment.flags))?;
+            self.set_word(header + 28, segment.align)
+        }
+    }
+
+    // move the program header at index from right behind the one at index after
+    fn move_program_header(&mut self, from: usize, after: usize) -> Result<(), ErrorKind> {
+        let (phoff, phentsize, phnum) = self.program_header_table()?;
+        let end = phoff
+            .checked_add(phentsize * phnum)
+            .ok_or(ErrorKind::MalformedElf)?;
+        let table = self
+            .data
+            .get(phoff..end)
+            .ok_or(ErrorKind::MalformedElf)?
+            .to_vec();
+        let mut entries = table.chunks(phentsize).collect::<Vec<_>>();
+        let entry = entries.remove(from);
+        entries.insert(if from < after { after } else { after + 1 }, entry);
+        self.data[phoff..end].copy_from_slice(&entries.concat());
+        Ok(())
+    }
+
+    fn set_section(
+        &mut self,
+        section: &Section,
+        offset: usize,
+        address: usize,
+        size: usize,
+    ) -> Result<(), ErrorKind> {
+        // sh_addr, sh_offset and sh_size
+        let (address_at, offset_at, size_at) = if self.is_64bit {
+            (16, 24, 32)
+        } else {
+            (12, 16, 20)
+        };
+        self.set_word(section.header + address_at, address)?;
+        self.set_word(section.header + offset_at, offset)?;
+        self.set_word(section.header + size_at, size)
+    }
+
+    // make the loader look for libraries in runpath, a ':' separated list of directories,
+    // instead of in the DT_RPATH or DT_RUNPATH the binary was linked with.
+    // the new path is written over the old one if it fits, otherwise .dynamic and .dynstr get
+    // copied to the end of the file with room for it, loaded by a new segment that takes the
+    // place of a PT_NOTE program header (notes are not needed to run a binary)
+    pub(crate) fn set_runpath(&mut self, runpath: &str) -> Result<(), ErrorKind> {
+        let dynamic = self
+            .section(SHT_DYNAMIC)
+            .cloned()
+            .ok_or(ErrorKind::NoRoomForRunpath)?;
+        let strings = self
+            .sections
+            .get(dynamic.link)
+            .cloned()
+            .ok_or(ErrorKind::MalformedElf)?;
+        let entries = self.dynamic_entries(&dynamic)?;
+        let entry_size = if self.is_64bit { 16 } else { 8 };
+        let word_size = entry_size / 2;
+
+        // the loader ignores DT_RPATH if there is a DT_RUNPATH
+        let old_path = entries
+            .iter()
+            .position(|(tag, _)| *tag == DT_RUNPATH)
+            .or_else(|| entries.iter().position(|(tag, _)| *tag == DT_RPATH));
+        // a DT_RPATH also applies to the dependencies of dependencies, keep it one
+        let tag = match old_path {
+            Some(index) if entries[index].0 == DT_RPATH => DT_RPATH,
+            _ => DT_RUNPATH,
+        };
+        if let Some(index) = old_path {
+            let old = self.string(dynamic.link, entries[index].1)?;
+            if runpath.len() <= old.len() {
+                let start = strings.offset + entries[index].1;
+                let mut bytes = runpath.as_bytes().to_vec();
+                bytes.resize(old.len(), 0);
+                self.data[start..start + bytes.len()].copy_from_slice(&bytes);
+                return Ok(());
+            }
+        }
+
+        let headers = self.program_headers()?;
+        let note = headers
+            .iter()
+            .rposition(|segment| segment.kind == PT_NOTE)
+            .ok_or(ErrorKind::NoRoomForRunpath)?;
+        let last_load = headers
+            .iter()
+            .rposition(|segment| segment.kind == PT_LOAD)
+            .ok_or(ErrorKind::MalformedElf)?;
+        let loads = headers.iter().filter(|segment| segment.kind == PT_LOAD);
+        let align = loads
+            .clone()
+            .map(|segment| segment.align)
+            .max()
+            .unwrap_or(0)
+            .max(0x1000);
+        let end = loads
+            .map(|segment| segment.vaddr.saturating_add(segment.memsz))
+            .max()
+            .unwrap_or(0);
+
+        let mut new_entries = entries
+            .into_iter()
+            .filter(|(tag, _)| *tag != DT_RPATH && *tag != DT_RUNPATH)
+            .collect::<Vec<_>>();
+        let old_strings = self.data[strings.offset..strings.offset + strings.size].to_vec();
+        let dynamic_size = (new_entries.len() + 2) * entry_size;
+        let strings_size = old_strings.len() + runpath.len() + 1;
+        // file offset and address have to be the same modulo the page size
+        let offset = (self.data.len() + 15) / 16 * 16;
+        let address = (end + align - 1) / align * align + offset % align;
+        let strings_address = address + dynamic_size;
+        let mut relocated = 0;
+        for entry in &mut new_entries {
+            match entry.0 {
+                DT_STRTAB => entry.1 = strings_address,
+                DT_STRSZ => entry.1 = strings_size,
+                _ => continue,
+            }
+            relocated += 1;
+        }
+        if relocated != 2 {
+            return Err(ErrorKind::MalformedElf);
+        }
+        new_entries.push((tag, old_strings.len()));
+        new_entries.push((DT_NULL, 0));
+
+        self.data.resize(offset + dynamic_size, 0);
+        for (index, (tag, value)) in new_entries.iter().enumerate() {
+            let entry = offset + index * entry_size;
+            self.set_uint(entry, word_size, *tag)?;
+            self.set_word(entry + word_size, *value)?;
+        }
+        self.data.extend_from_slice(&old_strings);
+        self.data.extend_from_slice(runpath.as_bytes());
+        self.data.push(0);
+
+        // the loader writes to .dynamic
+        let size = dynamic_size + strings_size;
+        self.set_program_header(&ProgramHeader {
+            header: headers[note].header,
+            kind: PT_LOAD,
+            flags: PF_R | PF_W,
+            offset,
+            vaddr: address,
+            filesz: size,
+            memsz: size,
+            align,
+        })?;
+        if let Some(segment) = headers.iter().find(|segment| segment.kind == PT_DYNAMIC) {
+            self.set_program_header(&ProgramHeader {
+                offset,
+                vaddr: address,
+                filesz: dynamic_size,
+                memsz: dynamic_size,
+                ..*segment
+            })?;
+        }
+        // PT_LOAD segments have to be sorted by address, the new one comes last
+        self.move_program_header(note, last_load)?;
+        self.set_section(&dynamic, offset, address, dynamic_size)?;
+        self.set_section(
+            &strings,
+            offset + dynamic_size,
+            strings_address,
+            strings_size,
+        )?;
+        self.sections = self.read_sections()?;
+        Ok(())
+    }
+
+    // the symbol versions this library provides, from .gnu.version_d
+    pub(crate) fn version_definitions(&self) -> Result<Vec<String>, ErrorKind> {
+        let section = match self.section(SHT_GNU_VERDEF) {
+            Some(section) => section,
+            None => return Ok(Vec::new()),
+        };
+        let mut definitions = Vec::new();
+        let mut entry = section.offset;
+        for _ in 0..section.info {
+            // Elf_Verdef: vd_version, vd_flags, vd_ndx, vd_cnt, vd_hash, vd_aux, vd_next
+            let flags = self.u16(entry + 2)?;
+            if flags & VER_FLG_BASE == 0 {
+                // the first Elf_Verdaux names the version, the others are its parents
+                let aux = entry + self.u32(entry + 12)? as usize;
+                definitions.push(self.string(section.link, self.u32(aux)? as usize)?);
+            }
+
+            let next = self.u32(entry + 16)? as usize;
+            if next == 0 {
+                break;
+            }
+            entry += next;
+        }
+        Ok(definitions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    #[test]
+    fn not_elf() {
+        assert_eq!(
+            Elf::parse(b"#!/bin/sh\necho hello\n".to_vec()).err(),
+            Some(ErrorKind::NotElf)
+        );
+        let mut truncated = b"\x7fELF\x02\x01\x01".to_vec();
+        truncated.resize(0x40, 0xff);
+        assert_eq!(Elf::parse(truncated).err(), Some(ErrorKind::MalformedElf));
+    }
+
+    #[test]
+    fn interpreter_and_architecture() {
+        let cargo_home = FakeCargoHome::new("elf_info")
+            .working_binary("fine")
+            .foreign_binary("foreign")
+            .build();
+
+        let info = Elf::read(&cargo_home.bin_dir().join("fine"))
+            .and_then(|elf| elf.info())
+            .unwrap();
+        assert!(!info.is_foreign());
+        assert_eq!(Some(info.arch()), ElfInfo::host_arch());
+        assert!(info.interpreter.is_some());
+        assert_eq!(info.missing_interpreter(), None);
+
+        let info = Elf::read(&cargo_home.bin_dir().join("foreign"))
+            .and_then(|elf| elf.info())
+            .unwrap();
+        assert!(info.is_foreign());
+
+        let info = ElfInfo {
+            is_64bit: false,
+            machine: EM_386,
+            interpreter: None,
+        };
+        assert_eq!(info.to_string(), "ELF 32-bit x86, statically linked");
+    }
+
+    #[test]
+    fn multilib_binaries_are_native() {
+        let (is_64bit, machine) = match native_arch().and_then(compat_arch) {
+            Some(compat) => compat,
+            None => return,
+        };
+        let mut info = ElfInfo {
+            is_64bit,
+            machine,
+            interpreter: None,
+        };
+        assert!(!info.is_foreign());
+        // any existing file will do as the loader
+        info.interpreter = Some(String::from("/"));
+        assert!(!info.is_foreign());
+        info.interpreter = Some(String::from("/nonexistent/ld-linux.so.2"));
+        assert!(info.is_foreign());
+    }
+
+    #[test]
+    fn version_requirements_and_definitions() {
+        let cargo_home = FakeCargoHome::new("elf_versions")
+            .version_mismatch_binary("needy", "libversioned.so")
+            .build();
+
+        let binary = Elf::read(&cargo_home.bin_dir().join("needy")).unwrap();
+        let requirements = binary.version_requirements().unwrap();
+        assert!(requirements.contains(&(
+            String::from("libversioned.so"),
+            vec![String::from("VERS_2")]
+        )));
+        // every binary linked against glibc needs some GLIBC_ version
+        assert!(requirements
+            .iter()
+            .any(|(file, versions)| file.starts_with("libc.so")
+                && versions.iter().all(|version| version.starts_with("GLIBC_"))));
+
+        let library = Elf::read(&cargo_home.path().join("build/libversioned.so")).unwrap();
+        assert_eq!(
+            library.version_definitions().unwrap(),
+            vec![String::from("VERS_1")]
+        );
+    }
+
+    #[test]
+    fn needed_libraries_and_runpath() {
+        let cargo_home = FakeCargoHome::new("elf_dynamic")
+            .version_mismatch_binary("needy", "libversioned.so")
+            .build();
+
+        let binary = Elf::read(&cargo_home.bin_dir().join("needy")).unwrap();
+        let dynamic = binary.dynamic().unwrap();
+        assert!(dynamic.needed.contains(&String::from("libversioned.so")));
+        assert!(dynamic
+            .needed
+            .iter()
+            .any(|library| library.starts_with("libc.so")));
+        // new linkers write DT_RUNPATH, old ones DT_RPATH
+        let build_dir = cargo_home.path().join("build").display().to_string();
+        assert!(dynamic.rpath.contains(&build_dir) || dynamic.runpath.contains(&build_dir));
+    }
+
+    #[test]
+    fn rewrite_runpath() {
+        let cargo_home = FakeCargoHome::new("elf_runpath")
+            .working_binary("fine")
+            .version_mismatch_binary("needy", "libversioned.so")
+            .build();
+        let patched = cargo_home.path().join("patched");
+        let run_patched = |elf: &Elf| {
+            fs::write(&patched, elf.bytes()).unwrap();
+            fs::set_permissions(&patched, fs::Permissions::from_mode(0o755)).unwrap();
+            Command::new(&patched).status().unwrap().success()
+        };
+
+        // no runpath yet, .dynamic and .dynstr get moved
+        let mut binary = Elf::read(&cargo_home.bin_dir().join("fine")).unwrap();
+        binary
+            .set_runpath("/rebuild-check/lib:$ORIGIN/../lib")
+            .unwrap();
+        assert!(run_patched(&binary));
+        let dynamic = Elf::read(&patched).unwrap().dynamic().unwrap();
+        assert_eq!(
+            dynamic.runpath,
+            vec!["/rebuild-check/lib", "$ORIGIN/../lib"]
+        );
+        assert!(dynamic
+            .needed
+            .iter()
+            .any(|library| library.starts_with("libc.so")));
+
+        // a shorter path fits where the old one was
+        let mut binary = Elf::read(&cargo_home.bin_dir().join("needy")).unwrap();
+        let size = binary.bytes().len();
+        binary.set_runpath("/lib").unwrap();
+        assert_eq!(binary.bytes().len(), size);
+        let dynamic = binary.dynamic().unwrap();
+        assert_eq!(dynamic.runpath, vec!["/lib"]);
+        assert!(dynamic.rpath.is_empty());
+    }
+
+    #[test]
+    fn rewrite_keeps_rpath() {
+        let cargo_home = FakeCargoHome::new("elf_rpath")
+            .rpath_chained_binary("chained", "libouter.so", "libinner.so")
+            .build();
+        let build_dir = cargo_home.path().join("build").display().to_string();
+        let patched = cargo_home.path().join("patched");
+        let run_patched = |elf: &Elf| {
+            fs::write(&patched, elf.bytes()).unwrap();
+            fs::set_permissions(&patched, fs::Permissions::from_mode(0o755)).unwrap();
+            Command::new(&patched).status().unwrap().success()
+        };
+
+        let binary = Elf::read(&cargo_home.bin_dir().join("chained")).unwrap();
+        assert_eq!(binary.dynamic().unwrap().rpath, vec![build_dir.clone()]);
+        assert!(run_patched(&binary));
+
+        // libouter.so only finds libinner.so as long as the binary's path stays a DT_RPATH,
+        // both when the new path fits in place and when it gets appended
+        for runpath in &[
+            build_dir.clone(),
+            format!("/rebuild-check/lib:{}", build_dir),
+        ] {
+            let mut binary = Elf::read(&cargo_home.bin_dir().join("chained")).unwrap();
+            binary.set_runpath(runpath).unwrap();
+            let dynamic = binary.dynamic().unwrap();
+            assert_eq!(dynamic.rpath.join(":"), *runpath);
+            assert!(dynamic.runpath.is_empty());
+            assert!(run_patched(&binary));
+        }
+    }
+
+    #[test]
+    fn undefined_and_exported_symbols() {
+        let cargo_home = FakeCargoHome::new("elf_symbols")
+            .version_mismatch_binary("needy", "libversioned.so")
+            .build();
+
+        let binary = Elf::read(&cargo_home.bin_dir().join("needy")).unwrap();
+        let undefined = binary.undefined_symbols().unwrap();
+        assert!(undefined.contains(&(String::from("foo"), Some(String::from("libversioned.so")))));
+        assert!(undefined
+            .iter()
+            .any(
+                |(_, library)| library.as_ref().map(|lib| lib.starts_with("libc.so")) == Some(true)
+            ));
+
+        let library = Elf::read(&cargo_home.path().join("build/libversioned.so")).unwrap();
+        let exported = library.exported_symbols().unwrap();
+        assert!(exported.contains(&String::from("foo")));
+        assert!(!exported.contains(&String::from("main")));
+    }
+}
@@ -5,4 +5,8 @@ pub(crate) enum ErrorKind {
     NoReadCratesToml,  // failed to read .crates.toml
     NotOpenCratesToml, // could not open file
     UnknownAPI,        // api changed, cargo-rebuild-check most likely incompatibe to file format
+    NotElf,            // file could not be read or is not an ELF file
+    MalformedElf,      // ELF file is truncated or contains offsets pointing nowhere
+    NoRoomForRunpath,  // neither the old DT_RUNPATH nor a PT_NOTE segment could be reused
+    NotWritten,        // could not back up or replace a binary
 }
@@ -0,0 +1,164 @@
+use std::path::Path;
+use std::slice;
+
+use crate::core::*;
+use crate::loader::*;
+use crate::parse::*;
+use crate::script::*;
+use crate::wrapper::*;
+
+fn print_tree(nodes: &[LibraryNode], depth: usize) {
+    let indent = "    ".repeat(depth + 1);
+    for node in nodes {
+        match node.path {
+            None => println!("{}{} => not found", indent, node.name),
+            Some(ref path) if node.seen => {
+                println!("{}{} => {} (see above)", indent, node.name, path.display())
+            }
+            Some(ref path) => println!(
+                "{}{} => {} ({})",
+                indent,
+                node.name,
+                path.display(),
+                node.rule
+                    .map_or("no search rule matches", SearchRule::label)
+            ),
+        }
+        if !node.seen {
+            print_tree(&node.children, depth + 1);
+        }
+    }
+}
+
+fn explain_binary(binary: &str, bin_dir: &Path, rust_lib_path: &str) {
+    let path = bin_dir.join(binary);
+    // a wrapped binary runs with the lib dirs of its wrapper, missing binaries and other
+    // scripts are explained by the report already
+    let (path, library_path) = match wrapped_binary(bin_dir, binary) {
+        Some(wrapper) => (wrapper.real.clone(), wrapper.library_path(rust_lib_path)),
+        None if !path.is_file() || read_shebang(&path).is_some() => return,
+        None => (path, rust_lib_path.to_string()),
+    };
+    // the same results the check is based on
+    let libraries = resolve_libraries(&path, &library_path);
+    let tree = match dependency_tree(&path, &libraries, &library_path, None) {
+        Ok(tree) => tree,
+        Err(_) => return,
+    };
+    // statically linked
+    if tree.is_empty() {
+        return;
+    }
+    println!("  Libraries of '{}':", binary);
+    print_tree(&tree, 0);
+    for chain in broken_chains(&tree) {
+        println!("  Chain breaks at: {} -> {}", binary, chain.join(" -> "));
+    }
+}
+
+pub(crate) fn explain_crates(packages: &[CrateInfo], rust_lib_path: &str, install_root: &Path) {
+    // every detail we can find out, and nothing from the cache
+    let options = CheckOptions {
+        deep: true,
+        verbose: true,
+        print_order: Some(SortOrder::Name),
+        ..CheckOptions::default()
+    };
+    let bin_dir = install_root.join("bin");
+    let reports = check_crates(packages, rust_lib_path, &bin_dir, None, &options);
+    for report in &reports {
+        print_reports(slice::from_ref(report));
+        for binary in &report.package.binaries {
+            explain_binary(binary, &bin_dir, rust_lib_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::*;
+
+    #[test]
+    fn explain_binary() {
+        let cargo_home = FakeCargoHome::new("explain")
+            .registry_crate("tls", "0.1.0", &["tls", "tls-helper"])
+            .working_binary("tls")
+            .broken_binary("tls-helper", "librebuild-check-test.so.1")
+            .working_crate("other", "0.1.0")
+            .build();
+
+        // a binary explains the whole crate it belongs to
+        let output = cargo_home.run(&["explain", "tls-helper"]);
+        let stdout = &output.stdout;
+        assert!(stdout.starts_with("  Checking crate tls 0.1.0\n"));
+        assert!(stdout.contains("    Binary 'tls-helper': ELF "));
+        assert!(stdout.contains(
+            "  Libraries of 'tls-helper':\n    librebuild-check-test.so.1 => not found\n"
+        ));
+        assert!(stdout.contains("  Chain breaks at: tls-helper -> librebuild-check-test.so.1\n"));
+        assert!(!stdout.contains("other"));
+        assert!(output.stderr.starts_with(
+            "    Binary 'tls-helper' is missing:\n\t\tlibrebuild-check-test.so.1 (system library)\n"
+        ));
+
+        let output = cargo_home.run(&["explain", "nothing"]);
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(
+            output.stderr,
+            "No installed crate or binary named 'nothing'\n"
+        );
+    }
+
+    #[test]
+    fn explain_transitive_library() {
+        let cargo_home = FakeCargoHome::new("explain_chain")
+            .registry_crate("chain", "0.1.0", &["chain"])
+            .chained_binary("chain", "libouter.so", "libinner.so")
+            .build();
+
+        let output = cargo_home.run(&["explain", "chain"]);
+        let stdout = &output.stdout;
+        let build_dir = cargo_home.path().join("build");
+        // new linkers write DT_RUNPATH, old ones DT_RPATH
+        let found_outer = |rule: &str| {
+            format!(
+                "  Libraries of 'chain':\n    libouter.so => {} ({})\n        libinner.so => not found\n",
+                build_dir.join("libouter.so").display(),
+                rule
+            )
+        };
+        assert!(
+            stdout.contains(&found_outer("RUNPATH")) || stdout.contains(&found_outer("RPATH")),
+            "{}",
+            stdout
+        );
+        assert!(stdout.contains(" (ld.so.cache)\n") || stdout.contains(" (default dirs)\n"));
+        assert!(stdout.contains(" (interpreter)\n"));
+        assert!(stdout.ends_with("  Chain breaks at: chain -> libouter.so -> libinner.so\n"));
+    }
+
+    #[test]
+    fn explain_wrapped_binary() {
+        let cargo_home = FakeCargoHome::new("explain_wrapped")
+            .broken_crate("pinned", "0.1.0", "libstd-0123456789abcdef.so")
+            .toolchain_library("old-x86_64-unknown-linux-gnu", "libstd-0123456789abcdef.so")
+            .with_rustup_env()
+            .build();
+        let lib_dir = cargo_home.toolchain_lib_dir("old-x86_64-unknown-linux-gnu");
+        assert!(cargo_home
+            .run(&["rebuild", "--fix", "wrapper"])
+            .status
+            .success());
+
+        // the tree of the real binary, as the wrapper runs it
+        let output = cargo_home.run(&["explain", "pinned"]);
+        assert!(
+            output.stdout.contains(&format!(
+                "  Libraries of 'pinned':\n    libstd-0123456789abcdef.so => {} (LD_LIBRARY_PATH)\n",
+                lib_dir.join("libstd-0123456789abcdef.so").display()
+            )),
+            "{}",
+            output.stdout
+        );
+    }
+}
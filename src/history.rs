@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::core::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CrateStatus {
+    Ok,
+    Broken,
+    // --rebuild-all does not check anything
+    Unchecked,
+    // only statically linked binaries, nothing that could break
+    Static,
+    // works, but only through wrappers that point it at another toolchain
+    Wrapped,
+    // links fine, but a binary failed when --smoke ran it
+    RuntimeBroken,
+}
+
+impl CrateStatus {
+    fn is_broken(self) -> bool {
+        self == CrateStatus::Broken || self == CrateStatus::RuntimeBroken
+    }
+}
+
+// what a run was started as
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RunKind {
+    Check,
+    Rebuild,
+    Watch,
+    // recorded before the kind of run was
+    Unknown,
+}
+
+impl Default for RunKind {
+    fn default() -> Self {
+        RunKind::Unknown
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct CrateRecord {
+    name: String,
+    version: String,
+    root: String,
+    status: CrateStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RebuildRecord {
+    name: String,
+    root: String,
+    duration_secs: f64,
+    success: bool,
+}
+
+// a single run of cargo-rebuild-check, appended as one line of json to
+// $CARGO_HOME/rebuild-check/history.jsonl
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct HistoryRecord {
+    timestamp: u64,
+    #[serde(default)]
+    kind: RunKind,
+    // every crate installed into these roots is in the record, a run of "rebuild <crates>" has
+    // none
+    #[serde(default)]
+    roots: Vec<String>,
+    toolchain: String,
+    sysroot: String,
+    crates: Vec<CrateRecord>,
+    rebuilds: Vec<RebuildRecord>,
+}
+
+fn history_file(cargo_home: &Path) -> PathBuf {
+    let mut path = cargo_home.to_path_buf();
+    path.push("rebuild-check");
+    path.push("history.jsonl");
+    path
+}
+
+impl HistoryRecord {
+    pub(crate) fn new(kind: RunKind, toolchain: String, sysroot: &str) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or(0);
+        Self {
+            timestamp,
+            kind,
+            roots: Vec::new(),
+            toolchain,
+            sysroot: sysroot.to_string(),
+            crates: Vec::new(),
+            rebuilds: Vec::new(),
+        }
+    }
+
+    pub(crate) fn has_broken_crates(&self) -> bool {
+        self.crates.iter().any(|record| record.status.is_broken())
+    }
+
+    // the crates of install_root were recorded without leaving any out
+    pub(crate) fn add_root(&mut self, install_root: &Path) {
+        self.roots.push(install_root.display().to_string());
+    }
+
+    pub(crate) fn add_crates(
+        &mut self,
+        install_root: &Path,
+        reports: &[CrateReport<'_>],
+        rebuild_all: bool,
+    ) {
+        for report in reports {
+            // a static binary can be broken too, only healthy crates count as static
+            let status = if report.runtime_broken {
+                CrateStatus::RuntimeBroken
+            } else if report.broken && !rebuild_all {
+                CrateStatus::Broken
+            } else if report.broken {
+                // --rebuild-all does not check the crates it rebuilds
+                CrateStatus::Unchecked
+            } else if report.static_only {
+                CrateStatus::Static
+            } else if rebuild_all {
+                CrateStatus::Unchecked
+            } else if report.wrapped {
+                CrateStatus::Wrapped
+            } else {
+                CrateStatus::Ok
+            };
+            self.crates.push(CrateRecord {
+                name: report.package.name.clone(),
+                version: report.package.version.clone(),
+                root: install_root.display().to_string(),
+                status,
+            });
+        }
+    }
+
+    pub(crate) fn add_rebuilds(&mut self, install_root: &Path, rebuilds: &[RebuildResult<'_>]) {
+        for rebuild in rebuilds {
+            self.rebuilds.push(RebuildRecord {
+                name: rebuild.package.name.clone(),
+                root: install_root.display().to_string(),
+                duration_secs: rebuild.duration.as_secs() as f64
+                    + f64::from(rebuild.duration.subsec_millis()) / 1000.0,
+                success: rebuild.success,
+            });
+        }
+    }
+
+    pub(crate) fn append_to(&self, cargo_home: &Path) {
+        // not being able to record the run should not make the check itself fail
+        let path = history_file(cargo_home);
+        if let Some(dir) = path.parent() {
+            if fs::create_dir_all(dir).is_err() {
+                return;
+            }
+        }
+        let line = match serde_json::to_string(self) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(&path) {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+pub(crate) fn read_history(cargo_home: &Path) -> Vec<HistoryRecord> {
+    match fs::read_to_string(history_file(cargo_home)) {
+        // skip lines we can't make sense of, for example from an interrupted write
+        Ok(content) => content
+            .lines()
+            .filter_map(|line| serde_json::from_str(line).ok())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// the last check of every crate in install_roots, what diff mode compares with
+pub(crate) fn read_baseline(cargo_home: &Path, install_roots: &[PathBuf]) -> Option<HistoryRecord> {
+    let roots = install_roots
+        .iter()
+        .map(|root| root.display().to_string())
+        .collect::<Vec<_>>();
+    read_history(cargo_home)
+        .into_iter()
+        .rev()
+        .find(|record| record.kind == RunKind::Check && record.roots == roots)
+}
+
+pub(crate) fn format_timestamp(timestamp: u64) -> String {
+    // seconds since epoch to "YYYY-MM-DD HH:MM:SS UTC", see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = timestamp / 86_400;
+    let seconds = timestamp % 86_400;
+
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+pub(crate) fn print_runs(records: &[HistoryRecord]) {
+    if records.is_empty() {
+        println!("  No runs recorded yet.");
+        return;
+    }
+    for record in records {
+        let broken = record
+            .crates
+            .iter()
+            .filter(|krate| krate.status.is_broken())
+            .count();
+        let mut summary = format!(
+            "  {}  {}  {} crates, {} broken",
+            format_timestamp(record.timestamp),
+            record.toolchain,
+            record.crates.len(),
+            broken
+        );
+        if !record.rebuilds.is_empty() {
+            let failed = record
+                .rebuilds
+                .iter()
+                .filter(|rebuild| !rebuild.success)
+                .count();
+            summary.push_str(&format!(
+                ", {} of {} rebuilds failed",
+                failed,
+                record.rebuilds.len()
+            ));
+        }
+        println!("{}", summary);
+    }
+}
+
+fn successful_rebuild<'a>(
+    records: &'a [HistoryRecord],
+    name: &str,
+    root: &str,
+) -> Option<(&'a HistoryRecord, &'a RebuildRecord)> {
+    records.iter().rev().find_map(|record| {
+        record
+            .rebuilds
+            .iter()
+            .find(|rebuild| rebuild.success && rebuild.name == name && rebuild.root == root)
+            .map(|rebuild| (record, rebuild))
+    })
+}
+
+pub(crate) fn print_crate_history(records: &[HistoryRecord], name: &str) {
+    // for each install root: index of the run in which the crate broke
+    let mut broken_since: HashMap<&str, usize> = HashMap::new();
+    let mut found = false;
+
+    for (index, record) in records.iter().enumerate() {
+        for krate in record.crates.iter().filter(|krate| krate.name == name) {
+            if !found {
+                println!("  History of crate {}:", name);
+                found = true;
+            }
+            let root = krate.root.as_str();
+            match krate.status {
+                status if status.is_broken() && !broken_since.contains_key(root) => {
+                    println!(
+                        "    {}  {} broke{} in '{}' ({})",
+                        format_timestamp(record.timestamp),
+                        krate.version,
+                        if status == CrateStatus::RuntimeBroken {
+                            " at runtime"
+                        } else {
+                            ""
+                        },
+                        root,
+                        record.toolchain
+                    );
+                    broken_since.insert(root, index);
+                }
+                CrateStatus::Ok | CrateStatus::Static | CrateStatus::Wrapped
+                    if broken_since.contains_key(root) =>
+                {
+                    let broke_at = broken_since.remove(root).unwrap();
+                    // the rebuild happens in a run in which the crate was still broken
+                    let cause = match successful_rebuild(&records[broke_at..index], name, root) {
+                        Some((rebuild_run, rebuild)) => format!(
+                            "rebuilt at {} (took {:.0}s)",
+                            format_timestamp(rebuild_run.timestamp),
+                            rebuild.duration_secs
+                        ),
+                        None if krate.status == CrateStatus::Wrapped => {
+                            String::from("wrapped to load its libraries from another toolchain")
+                        }
+                        None if records[broke_at].toolchain != record.toolchain => {
+                            format!("toolchain changed to {}", record.toolchain)
+                        }
+                        None => String::from("reinstalled or libraries restored"),
+                    };
+                    println!(
+                        "    {}  {} fixed in '{}': {}",
+                        format_timestamp(record.timestamp),
+                        krate.version,
+                        root,
+                        cause
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if !found {
+        println!("  No recorded runs of crate '{}'.", name);
+    }
+}
+
+pub(crate) fn print_repeated_failures(records: &[HistoryRecord]) {
+    // name -> (rebuilds, failed rebuilds, time of last failure)
+    let mut stats: HashMap<&str, (usize, usize, u64)> = HashMap::new();
+    for record in records {
+        for rebuild in &record.rebuilds {
+            let entry = stats.entry(&rebuild.name).or_insert((0, 0, 0));
+            entry.0 += 1;
+            if !rebuild.success {
+                entry.1 += 1;
+                entry.2 = record.timestamp;
+            }
+        }
+    }
+
+    let mut repeated = stats
+        .into_iter()
+        .filter(|(_, (_, failed, _))| *failed > 1)
+        .collect::<Vec<_>>();
+    if repeated.is_empty() {
+        println!("  No crate failed to rebuild more than once.");
+        return;
+    }
+    // most failures first
+    repeated.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+
+    println!("  Crates failing to rebuild repeatedly:");
+    for (name, (total, failed, last_failure)) in repeated {
+        println!(
+            "    {}: {} of {} rebuilds failed, last failure at {}",
+            name,
+            failed,
+            total,
+            format_timestamp(last_failure)
+        );
+    }
+}
+
+// status of every crate per install root, in the order the crates were recorded
+fn statuses_by_root(record: &HistoryRecord) -> Vec<(&str, Vec<(&str, CrateStatus)>)> {
+    let mut roots: Vec<(&str, Vec<(&str, CrateStatus)>)> = Vec::new();
+    for krate in &record.crates {
+        let position = match roots.iter().position(|(root, _)| *root == krate.root) {
+            Some(position) => position,
+            None => {
+                roots.push((&krate.root, Vec::new()));
+                roots.len() - 1
+            }
+        };
+        roots[position].1.push((&krate.name, krate.status));
+    }
+    roots
+}
+
+// crates that were not checked can't have changed as far as we know
+fn checked_statuses(record: &HistoryRecord) -> Vec<(&str, Vec<(&str, CrateStatus)>)> {
+    let mut roots = statuses_by_root(record);
+    for (_, crates) in &mut roots {
+        crates.retain(|(_, status)| *status != CrateStatus::Unchecked);
+    }
+    roots
+}
+
+// what changed since the previous run, prints nothing if nothing changed
+pub(crate) fn print_changes(previous: Option<&HistoryRecord>, current: &HistoryRecord) {
+    let previous_roots = previous.map(checked_statuses).unwrap_or_default();
+    let current_roots = checked_statuses(current);
+
+    for (root, crates) in &current_roots {
+        let previous_crates = previous_roots
+            .iter()
+            .find(|(previous_root, _)| previous_root == root)
+            .map(|(_, crates)| crates.as_slice())
+            .unwrap_or(&[]);
+        let previous_status = |name: &str| {
+            previous_crates
+                .iter()
+                .find(|(previous_name, _)| *previous_name == name)
+                .map(|(_, status)| *status)
+        };
+
+        let mut newly_broken = Vec::new();
+        let mut newly_fixed = Vec::new();
+        let mut still_broken = Vec::new();
+        let mut new_crates = Vec::new();
+        for (name, status) in crates {
+            let before = previous_status(name);
+            if before.is_none() {
+                new_crates.push(*name);
+            }
+            match (before, status) {
+                (Some(before), status) if before.is_broken() && status.is_broken() => {
+                    still_broken.push(*name)
+                }
+                (_, status) if status.is_broken() => newly_broken.push(*name),
+                (Some(before), CrateStatus::Ok)
+                | (Some(before), CrateStatus::Static)
+                | (Some(before), CrateStatus::Wrapped)
+                    if before.is_broken() =>
+                {
+                    newly_fixed.push(*name)
+                }
+                _ => {}
+            }
+        }
+        // only a run that saw every crate of the root knows which ones are gone
+        let removed_crates = if current
+            .roots
+            .iter()
+            .any(|current_root| current_root == root)
+        {
+            previous_crates
+                .iter()
+                .map(|(name, _)| *name)
+                .filter(|name| !crates.iter().any(|(current_name, _)| current_name == name))
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
+
+        let changes = [
+            ("Newly broken", newly_broken),
+            ("Newly fixed", newly_fixed),
+            ("Still broken", still_broken),
+            ("New crates", new_crates),
+            ("Removed crates", removed_crates),
+        ];
+        // "still broken" alone is not a change
+        if changes
+            .iter()
+            .all(|(label, names)| names.is_empty() || *label == "Still broken")
+        {
+            continue;
+        }
+        if current_roots.len() > 1 {
+            println!("  Install root '{}'", root);
+        }
+        for (label, names) in &changes {
+            if !names.is_empty() {
+                println!("  {}: {}", label, names.join(" "));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn record(timestamp: u64, toolchain: &str, crates: &[(&str, CrateStatus)]) -> HistoryRecord {
+        HistoryRecord {
+            timestamp,
+            kind: RunKind::Check,
+            roots: vec![String::from("/root/.cargo")],
+            toolchain: toolchain.to_string(),
+            sysroot: String::from("/sysroot/lib"),
+            crates: crates
+                .iter()
+                .map(|(name, status)| CrateRecord {
+                    name: name.to_string(),
+                    version: String::from("0.1.0"),
+                    root: String::from("/root/.cargo"),
+                    status: *status,
+                })
+                .collect(),
+            rebuilds: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_530_000_000), "2018-06-26 08:00:00 UTC");
+    }
+
+    #[test]
+    fn history_records_runs() {
+        let cargo_home = FakeCargoHome::new("history")
+            .registry_crate("fine", "1.0.0", &["fine"])
+            .registry_crate("broken", "0.2.0", &["broken"])
+            .working_binary("fine")
+            .broken_binary("broken", "libfoo-0123456789abcdef.so")
+            .build();
+
+        cargo_home.run(&[]);
+        cargo_home.run(&[]);
+
+        let records = read_history(cargo_home.path());
+        assert_eq!(records.len(), 2);
+        let statuses = records[1]
+            .crates
+            .iter()
+            .map(|krate| (krate.name.as_str(), krate.status))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![("broken", CrateStatus::Broken), ("fine", CrateStatus::Ok)]
+        );
+        assert!(records[1].rebuilds.is_empty());
+
+        let output = cargo_home.run(&["history", "broken"]);
+        let stdout = &output.stdout;
+        let lines = stdout.lines().collect::<Vec<_>>();
+        // broke in the first run, still broken in the second one
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "  History of crate broken:");
+        assert!(lines[1].contains(&format!(
+            "UTC  0.2.0 broke in '{}' (rustc ",
+            cargo_home.path().display()
+        )));
+
+        let output = cargo_home.run(&["history", "--failures"]);
+        let stdout = &output.stdout;
+        assert_eq!(stdout, "  No crate failed to rebuild more than once.\n");
+    }
+
+    #[test]
+    fn diff_reports_changes() {
+        let cargo_home = FakeCargoHome::new("diff")
+            .registry_crate("fine", "1.0.0", &["fine"])
+            .registry_crate("broken", "0.2.0", &["broken"])
+            .working_binary("fine")
+            .broken_binary("broken", "libfoo-0123456789abcdef.so")
+            .build();
+
+        let output = cargo_home.run(&["--diff"]);
+        assert_eq!(
+            output.stdout,
+            "  Newly broken: broken\n  New crates: broken fine\n"
+        );
+        assert!(output.stderr.is_empty());
+
+        // nothing changed
+        let output = cargo_home.run(&["--diff"]);
+        assert!(output.stdout.is_empty());
+
+        // a check of other install roots is no baseline
+        let other_root = FakeCargoHome::new("diff_other")
+            .working_crate("elsewhere", "0.1.0")
+            .build();
+        let other_root = other_root.path().to_str().unwrap();
+        assert!(cargo_home
+            .run(&["check", "--full", "--root", other_root])
+            .status
+            .success());
+        let output = cargo_home.run(&["--diff"]);
+        assert!(output.stdout.is_empty());
+
+        let cargo_home = cargo_home
+            .working_crate("other", "0.1.0")
+            .working_binary("broken")
+            .build();
+        let output = cargo_home.run(&["--diff"]);
+        assert_eq!(
+            output.stdout,
+            "  Newly fixed: broken\n  New crates: other\n"
+        );
+    }
+
+    #[test]
+    fn crate_fixed_by_rebuild() {
+        let mut broken = record(100, "rustc 1.0", &[("clippy", CrateStatus::Broken)]);
+        broken.rebuilds.push(RebuildRecord {
+            name: String::from("clippy"),
+            root: String::from("/root/.cargo"),
+            duration_secs: 42.0,
+            success: true,
+        });
+        let records = vec![
+            record(0, "rustc 1.0", &[("clippy", CrateStatus::Ok)]),
+            broken,
+            record(200, "rustc 1.0", &[("clippy", CrateStatus::Ok)]),
+        ];
+        let rebuild = successful_rebuild(&records[1..2], "clippy", "/root/.cargo");
+        assert_eq!(rebuild.map(|(run, _)| run.timestamp), Some(100));
+        assert!(successful_rebuild(&records, "clippy", "/other/root").is_none());
+    }
+}
@@ -0,0 +1,114 @@
+// crates that ship with rustc besides rustc_* and syntax*, their dylibs live in the sysroot
+const TOOLCHAIN_CRATES: &[&str] = &[
+    "std",
+    "core",
+    "alloc",
+    "test",
+    "term",
+    "getopts",
+    "arena",
+    "fmt_macros",
+    "graphviz",
+    "serialize",
+    "proc_macro",
+];
+
+// where a library that could not be found comes from, this decides what fixes the binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LibraryKind {
+    // part of the rustc sysroot, for example libstd-<hash>.so or librustc_driver-<hash>.so
+    RustToolchain,
+    // any other dylib built by rustc, "lib<crate>-<hash>.so"
+    RustDylib,
+    // everything else, for example libssl.so.1.0.0
+    System,
+}
+
+impl LibraryKind {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            LibraryKind::RustToolchain => "rust toolchain",
+            LibraryKind::RustDylib => "rust library",
+            LibraryKind::System => "system library",
+        }
+    }
+
+    pub(crate) fn remedy(self) -> &'static str {
+        match self {
+            LibraryKind::RustToolchain => {
+                "rebuild the crate with the current toolchain, or reinstall the toolchain it was built with"
+            }
+            LibraryKind::RustDylib => "rebuild the crate",
+            LibraryKind::System => {
+                "install the package providing the library (and its -dev package), then rebuild the crate"
+            }
+        }
+    }
+}
+
+// "libstd-0cfbe79f10411924.so" -> Some("std")
+fn hashed_dylib_name(library: &str) -> Option<&str> {
+    if !library.starts_with("lib") || !library.ends_with(".so") || library.len() < 6 {
+        return None;
+    }
+    let stem = &library[3..library.len() - 3];
+    let dash = stem.rfind('-')?;
+    let hash = &stem[dash + 1..];
+    if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(&stem[..dash])
+    } else {
+        None
+    }
+}
+
+pub(crate) fn classify_library(library: &str) -> LibraryKind {
+    // rustc links against its own LLVM, "libLLVM-6.0.so" or "libLLVM-17-rust-1.75.0-stable.so"
+    if library.starts_with("libLLVM-") {
+        return LibraryKind::RustToolchain;
+    }
+    match hashed_dylib_name(library) {
+        Some(name)
+            if name.starts_with("rustc")
+                || name.starts_with("syntax")
+                || TOOLCHAIN_CRATES.contains(&name) =>
+        {
+            LibraryKind::RustToolchain
+        }
+        Some(_) => LibraryKind::RustDylib,
+        None => LibraryKind::System,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_libraries() {
+        let toolchain = [
+            "libstd-0cfbe79f10411924.so",
+            "librustc_driver-6516506ab0349d45.so",
+            "libsyntax_pos-610524479a0d36fa.so",
+            "libLLVM-6.0.so",
+        ];
+        for library in &toolchain {
+            assert_eq!(classify_library(library), LibraryKind::RustToolchain);
+        }
+        assert_eq!(
+            classify_library("libfoo-0123456789abcdef.so"),
+            LibraryKind::RustDylib
+        );
+
+        let system = [
+            "libssl.so.1.0.0",
+            "libgit2.so.26",
+            "libc.so.6",
+            // not a rustc hash
+            "libfoo-1.2.so",
+            "libstd-0cfbe79f1041192.so",
+        ];
+        for library in &system {
+            assert_eq!(classify_library(library), LibraryKind::System);
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::elf::*;
+use crate::errors::*;
+
+const LD_SO_CACHE: &str = "/etc/ld.so.cache";
+// the current format, possibly preceded by the one from before glibc 2.2 ("ld.so-1.7.0")
+const LD_SO_CACHE_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+const LD_SO_CACHE_HEADER_SIZE: usize = 48;
+const LD_SO_CACHE_ENTRY_SIZE: usize = 24;
+
+// searched after everything else, the multiarch dirs are in ld.so.cache
+const DEFAULT_DIRS: &[&str] = &["/lib", "/usr/lib", "/lib64", "/usr/lib64"];
+
+// which rule of the dynamic loader found a library, in the order they are tried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SearchRule {
+    // the program interpreter is loaded by the kernel before anything else
+    Interpreter,
+    // DT_NEEDED is a path, not a soname
+    Path,
+    Rpath,
+    LdLibraryPath,
+    Runpath,
+    LdSoCache,
+    DefaultDirs,
+}
+
+impl SearchRule {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            SearchRule::Interpreter => "interpreter",
+            SearchRule::Path => "path",
+            SearchRule::Rpath => "RPATH",
+            SearchRule::LdLibraryPath => "LD_LIBRARY_PATH",
+            SearchRule::Runpath => "RUNPATH",
+            SearchRule::LdSoCache => "ld.so.cache",
+            SearchRule::DefaultDirs => "default dirs",
+        }
+    }
+}
+
+// a library in the dependency tree of a binary
+#[derive(Debug)]
+pub(crate) struct LibraryNode {
+    pub(crate) name: String,
+    // None if it was not found
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) rule: Option<SearchRule>,
+    // libraries are loaded once, their dependencies are only listed the first time
+    pub(crate) seen: bool,
+    pub(crate) children: Vec<LibraryNode>,
+}
+
+// a binary or library that loads others, with $ORIGIN expanded in its search paths
+struct Loader {
+    rpath: Vec<PathBuf>,
+    runpath: Vec<PathBuf>,
+}
+
+impl Loader {
+    fn new(path: &Path, dynamic: &DynamicInfo) -> Self {
+        let origin = path
+            .parent()
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        let expand = |dirs: &[String]| {
+            dirs.iter()
+                .filter(|dir| !dir.is_empty())
+                .map(|dir| {
+                    PathBuf::from(
+                        dir.replace("${ORIGIN}", &origin)
+                            .replace("$ORIGIN", &origin),
+                    )
+                })
+                .collect()
+        };
+        Self {
+            rpath: expand(&dynamic.rpath),
+            runpath: expand(&dynamic.runpath),
+        }
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn native_u32(bytes: &[u8]) -> u32 {
+    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// soname -> paths, there can be one per architecture
+fn parse_ld_so_cache(data: &[u8]) -> HashMap<String, Vec<PathBuf>> {
+    let mut cache: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let base = match data
+        .windows(LD_SO_CACHE_MAGIC.len())
+        .position(|window| window == LD_SO_CACHE_MAGIC)
+    {
+        Some(base) => base,
+        None => return cache,
+    };
+    let u32_at = |offset: usize| data.get(offset..offset + 4).map(native_u32);
+    // strings are NUL terminated, at offsets relative to the header
+    let string_at = |offset: u32| -> Option<String> {
+        let bytes = data.get(base + offset as usize..)?;
+        let length = bytes.iter().position(|byte| *byte == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..length]).into_owned())
+    };
+
+    let count = u32_at(base + 20).unwrap_or(0) as usize;
+    for index in 0..count {
+        // file_entry_new: flags, key, value, osversion, hwcap
+        let entry = base + LD_SO_CACHE_HEADER_SIZE + index * LD_SO_CACHE_ENTRY_SIZE;
+        let (key, value) = match (u32_at(entry + 4), u32_at(entry + 8)) {
+            (Some(key), Some(value)) => (key, value),
+            _ => break,
+        };
+        if let (Some(name), Some(path)) = (string_at(key), string_at(value)) {
+            cache.entry(name).or_default().push(PathBuf::from(path));
+        }
+    }
+    cache
+}
+
+fn read_ld_so_cache(path: &Path) -> HashMap<String, Vec<PathBuf>> {
+    match fs::read(path) {
+        Ok(data) => parse_ld_so_cache(&data),
+        // no cache, the loader does without it as well
+        Err(_) => HashMap::new(),
+    }
+}
+
+struct Resolver<'a> {
+    // what the loader (ldd) resolved every library to, None if it was not found
+    libraries: &'a [(String, Option<PathBuf>)],
+    ld_library_path: Vec<PathBuf>,
+    ld_so_cache: HashMap<String, Vec<PathBuf>>,
+    interpreter: Option<PathBuf>,
+    // libraries in this dir are treated as gone, the search goes on as the loader's would
+    excluded: Option<&'a Path>,
+    // class and machine of the binary, libraries for other ones are skipped by the loader
+    arch: (bool, u16),
+}
+
+impl Resolver<'_> {
+    // the file the loader would load for "name" without the excluded dir
+    fn search(&self, loaders: &[Loader], name: &str, excluded: &Path) -> Option<PathBuf> {
+        let mut candidates = Vec::new();
+        if name.contains('/') {
+            candidates.push(PathBuf::from(name));
+        } else if let Some(loader) = loaders.last() {
+            let mut dirs = Vec::new();
+            if loader.runpath.is_empty() {
+                for loader in loaders
+                    .iter()
+                    .rev()
+                    .filter(|loader| loader.runpath.is_empty())
+                {
+                    dirs.extend(loader.rpath.iter().cloned());
+                }
+            }
+            dirs.extend(self.ld_library_path.iter().cloned());
+            dirs.extend(loader.runpath.iter().cloned());
+            candidates.extend(dirs.iter().map(|dir| dir.join(name)));
+            candidates.extend(self.ld_so_cache.get(name).cloned().unwrap_or_default());
+            candidates.extend(DEFAULT_DIRS.iter().map(|dir| Path::new(dir).join(name)));
+        }
+        candidates.into_iter().find(|path| {
+            !path.starts_with(excluded)
+                && Elf::read(path)
+                    .and_then(|elf| elf.info())
+                    .map(|info| (info.is_64bit, info.machine) == self.arch)
+                    .unwrap_or(false)
+        })
+    }
+
+    // the first rule that leads to the file the loader picked
+    fn search_rule(&self, loaders: &[Loader], name: &str, path: &Path) -> Option<SearchRule> {
+        if self
+            .interpreter
+            .as_ref()
+            .map_or(false, |interpreter| same_file(interpreter, path))
+        {
+            return Some(SearchRule::Interpreter);
+        }
+        if name.contains('/') {
+            return Some(SearchRule::Path);
+        }
+        let loader = loaders.last()?;
+        let in_dirs = |dirs: &[PathBuf]| dirs.iter().any(|dir| same_file(&dir.join(name), path));
+
+        // RPATH of the loading object and then its loaders, unless the loading object has a RUNPATH
+        if loader.runpath.is_empty()
+            && loaders
+                .iter()
+                .rev()
+                .any(|loader| loader.runpath.is_empty() && in_dirs(&loader.rpath))
+        {
+            Some(SearchRule::Rpath)
+        } else if in_dirs(&self.ld_library_path) {
+            Some(SearchRule::LdLibraryPath)
+        } else if in_dirs(&loader.runpath) {
+            Some(SearchRule::Runpath)
+        } else if self.ld_so_cache.get(name).map_or(false, |paths| {
+            paths.iter().any(|cached| same_file(cached, path))
+        }) {
+            Some(SearchRule::LdSoCache)
+        } else if DEFAULT_DIRS
+            .iter()
+            .any(|dir| same_file(&Path::new(dir).join(name), path))
+        {
+            Some(SearchRule::DefaultDirs)
+        } else {
+            None
+        }
+    }
+
+    fn node(
+        &self,
+        loaders: &mut Vec<Loader>,
+        name: &str,
+        seen: &mut HashSet<String>,
+    ) -> LibraryNode {
+        let found = self.libraries.iter().find(|(library, _)| library == name);
+        let mut path = found.and_then(|(_, path)| path.clone());
+        // ldd knows nothing about libraries only the replacement of an excluded one needs
+        if let Some(excluded) = self.excluded {
+            if found.is_none()
+                || path
+                    .as_ref()
+                    .map_or(false, |path| path.starts_with(excluded))
+            {
+                path = self.search(loaders, name, excluded);
+            }
+        }
+        let rule = path
+            .as_ref()
+            .and_then(|path| self.search_rule(loaders, name, path));
+        let mut node = LibraryNode {
+            name: name.to_string(),
+            path,
+            rule,
+            seen: !seen.insert(name.to_string()),
+            children: Vec::new(),
+        };
+        if node.seen {
+            return node;
+        }
+        let dynamic = node
+            .path
+            .as_ref()
+            .and_then(|path| Elf::read(path).and_then(|elf| elf.dynamic()).ok());
+        if let (Some(path), Some(dynamic)) = (node.path.as_ref(), dynamic) {
+            loaders.push(Loader::new(path, &dynamic));
+            node.children = dynamic
+                .needed
+                .iter()
+                .map(|needed| self.node(loaders, needed, seen))
+                .collect();
+            loaders.pop();
+        }
+        node
+    }
+}
+
+// the libraries of a binary and the ones they need in turn, using the paths the loader found
+// ("libraries") and telling which search rule led there; libraries found in "excluded" are
+// looked up again as if that dir did not exist
+pub(crate) fn dependency_tree(
+    binary_path: &Path,
+    libraries: &[(String, Option<PathBuf>)],
+    ld_library_path: &str,
+    excluded: Option<&Path>,
+) -> Result<Vec<LibraryNode>, ErrorKind> {
+    let binary = Elf::read(binary_path)?;
+    let dynamic = binary.dynamic()?;
+    let info = binary.info()?;
+    let resolver = Resolver {
+        libraries,
+        ld_library_path: env::split_paths(ld_library_path).collect(),
+        ld_so_cache: read_ld_so_cache(Path::new(LD_SO_CACHE)),
+        arch: (info.is_64bit, info.machine),
+        interpreter: info.interpreter.map(PathBuf::from),
+        excluded,
+    };
+    let mut loaders = vec![Loader::new(binary_path, &dynamic)];
+    let mut seen = HashSet::new();
+    Ok(dynamic
+        .needed
+        .iter()
+        .map(|needed| resolver.node(&mut loaders, needed, &mut seen))
+        .collect())
+}
+
+// the names from the binary down to every library "wanted" is true for
+pub(crate) fn chains_to<'a, F>(nodes: &'a [LibraryNode], wanted: &F) -> Vec<Vec<&'a str>>
+where
+    F: Fn(&LibraryNode) -> bool,
+{
+    let mut chains = Vec::new();
+    for node in nodes {
+        if wanted(node) {
+            chains.push(vec![node.name.as_str()]);
+        }
+        for mut chain in chains_to(&node.children, wanted) {
+            chain.insert(0, node.name.as_str());
+            chains.push(chain);
+        }
+    }
+    chains
+}
+
+// every library in the tree once, None if it was not found
+pub(crate) fn all_libraries(nodes: &[LibraryNode]) -> Vec<(String, Option<PathBuf>)> {
+    let mut libraries = Vec::new();
+    for node in nodes.iter().filter(|node| !node.seen) {
+        libraries.push((node.name.clone(), node.path.clone()));
+        libraries.extend(all_libraries(&node.children));
+    }
+    libraries
+}
+
+// the names from the binary down to every library that was not found
+pub(crate) fn broken_chains(nodes: &[LibraryNode]) -> Vec<Vec<&str>> {
+    chains_to(nodes, &|node: &LibraryNode| node.path.is_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ld_so_cache(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut strings = Vec::new();
+        let mut entry_data = Vec::new();
+        let strings_start = LD_SO_CACHE_HEADER_SIZE + entries.len() * LD_SO_CACHE_ENTRY_SIZE;
+        let push_u32 =
+            |data: &mut Vec<u8>, value: u32| data.extend_from_slice(&value.to_ne_bytes());
+        for (name, path) in entries {
+            let key = strings_start + strings.len();
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            let value = strings_start + strings.len();
+            strings.extend_from_slice(path.as_bytes());
+            strings.push(0);
+            // flags, key, value, osversion, hwcap
+            push_u32(&mut entry_data, 0x0303);
+            push_u32(&mut entry_data, key as u32);
+            push_u32(&mut entry_data, value as u32);
+            entry_data.extend_from_slice(&[0; 12]);
+        }
+        let mut data = LD_SO_CACHE_MAGIC.to_vec();
+        push_u32(&mut data, entries.len() as u32);
+        push_u32(&mut data, strings.len() as u32);
+        data.resize(LD_SO_CACHE_HEADER_SIZE, 0);
+        data.extend(entry_data);
+        data.extend(strings);
+        data
+    }
+
+    #[test]
+    fn read_ld_so_cache_entries() {
+        let data = ld_so_cache(&[
+            ("libc.so.6", "/lib/x86_64-linux-gnu/libc.so.6"),
+            ("libc.so.6", "/lib/i386-linux-gnu/libc.so.6"),
+            ("libz.so.1", "/lib/x86_64-linux-gnu/libz.so.1"),
+        ]);
+        let cache = parse_ld_so_cache(&data);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache["libc.so.6"].len(), 2);
+        assert_eq!(
+            cache["libz.so.1"],
+            vec![PathBuf::from("/lib/x86_64-linux-gnu/libz.so.1")]
+        );
+
+        // the old format in front of it does not matter
+        let mut data_with_old_format = b"ld.so-1.7.0\0\0\0\0\0".to_vec();
+        data_with_old_format.extend(data);
+        assert_eq!(parse_ld_so_cache(&data_with_old_format), cache);
+
+        assert!(parse_ld_so_cache(b"not a cache").is_empty());
+        // truncated
+        assert!(parse_ld_so_cache(LD_SO_CACHE_MAGIC).is_empty());
+    }
+
+    #[test]
+    fn chains_to_missing_libraries() {
+        let node = |name: &str, found: bool, children| LibraryNode {
+            name: name.to_string(),
+            path: if found {
+                Some(PathBuf::from("/lib").join(name))
+            } else {
+                None
+            },
+            rule: None,
+            seen: false,
+            children,
+        };
+        let tree = vec![
+            node("liba.so", true, vec![node("libb.so", false, Vec::new())]),
+            node("libc.so.6", true, Vec::new()),
+            node("libd.so", false, Vec::new()),
+        ];
+        assert_eq!(
+            broken_chains(&tree),
+            vec![vec!["liba.so", "libb.so"], vec!["libd.so"]]
+        );
+    }
+}
@@ -37,60 +37,462 @@
 )]
 #![cfg_attr(feature = "cargo-clippy", warn(needless_borrow))]
 
+mod cache;
 mod check_external_cmds;
 mod cli;
 mod core;
+mod doctor;
+mod elf;
 mod errors;
+mod explain;
+mod history;
+mod libs;
+mod loader;
+mod packages;
 mod parse;
+mod relink;
+mod script;
+mod smoke;
 #[cfg(test)]
 mod test_helpers;
+mod toolchains;
+mod watch;
+mod who_needs;
+mod wrapper;
 
+use crate::cache::*;
 use crate::check_external_cmds::*;
 use crate::cli::*;
 use crate::core::*;
+use crate::doctor::*;
+use crate::explain::*;
+use crate::history::*;
 use crate::parse::*;
+use crate::smoke::*;
+use crate::toolchains::*;
+use crate::watch::*;
+use crate::who_needs::*;
+
+use clap::ArgMatches;
+use std::path::{Path, PathBuf};
 
 // deserialize the ~/.cargo/.crates.toml
 
-fn main() {
-    match all_binaries_available() {
-        Ok(_) => {}
-        Err(missing_bins) => {
-            eprintln!("Could not find the following binaries: '{}'", missing_bins);
-            eprintln!("Please make them available in your $PATH.");
-            std::process::exit(1);
+fn exit_on_error<T>(result: Result<T, errors::ErrorKind>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            eprintln!("bad error: {:?}", error);
+            std::process::exit(3);
         }
     }
+}
 
-    let cfg = gen_clap();
-    // we need this in case we call "cargo-rebuild-check" directly
-    let cfg = cfg.subcommand_matches("rebuild-check").unwrap_or(&cfg);
+fn install_roots(cfg: &ArgMatches<'_>) -> Vec<PathBuf> {
+    let explicit_roots: Vec<PathBuf> = cfg
+        .values_of("root")
+        .map(|roots| roots.map(PathBuf::from).collect())
+        .unwrap_or_default();
+    exit_on_error(get_install_roots(explicit_roots))
+}
+
+// reuse the results of previous runs for binaries that did not change, unless --no-cache
+fn load_cache(cfg: &ArgMatches<'_>, cargo_home: &Path, rust_lib_path: &str) -> Option<Cache> {
+    if cfg.is_present("no-cache") {
+        None
+    } else {
+        Some(Cache::load(cargo_home, rust_lib_path))
+    }
+}
 
-    let cargo_cfg = cargo::util::config::Config::default().unwrap();
-    let mut bin_dir = cargo_cfg.home().clone().into_path_unlocked();
-    bin_dir.push("bin");
+// the options that check, rebuild and watch share
+fn check_options(cfg: &ArgMatches<'_>) -> CheckOptions {
+    CheckOptions {
+        deep: cfg.is_present("deep"),
+        skip_static: cfg.is_present("skip-static"),
+        verbose: cfg.is_present("verbose"),
+        cargo_flags: ["--locked", "--offline"]
+            .iter()
+            .filter(|flag| cfg.is_present(&flag[2..]))
+            .map(|flag| flag.to_string())
+            .collect(),
+        git_policy: match cfg.value_of("git-policy") {
+            Some("follow") => GitPolicy::Follow,
+            _ => GitPolicy::Exact,
+        },
+        smoke: if cfg.is_present("smoke") {
+            Some(SmokeConfig::load())
+        } else {
+            None
+        },
+        ..CheckOptions::default()
+    }
+}
 
-    // get vector of packages from parsed .crates.toml file
+// find out before checking anything if the cargo we have can rebuild that way
+fn check_cargo_flags(cargo_flags: &[String]) {
+    if cargo_flags.is_empty() {
+        return;
+    }
+    let cargo = cargo_version();
+    for flag in cargo_flags {
+        if let Err(reason) = check_cargo_flag(flag, cargo) {
+            eprintln!("{}", reason);
+            std::process::exit(3);
+        }
+    }
+}
+
+fn sort_order(cfg: &ArgMatches<'_>) -> SortOrder {
+    match cfg.value_of("sort") {
+        Some("status") => SortOrder::Status,
+        Some("size") => SortOrder::Size,
+        _ => SortOrder::Name,
+    }
+}
 
-    let file = read_crates_toml();
-    let packages = match get_installed_crate_information(file) {
+fn installed_crates(install_root: &Path) -> Vec<CrateInfo> {
+    // get vector of packages from parsed .crates.toml file
+    let file = read_crates_toml(install_root);
+    match get_installed_crate_information(file) {
         Ok(pkgs) => pkgs,
-        Err(error) => if let errors::ErrorKind::UnknownAPI = error {
-            std::process::exit(2);
-        } else {
-            eprintln!("bad error: {:?}", error);
+        Err(error) => {
+            if let errors::ErrorKind::UnknownAPI = error {
+                std::process::exit(2);
+            } else {
+                eprintln!("bad error: {:?}", error);
+                std::process::exit(3);
+            }
+        }
+    }
+}
+
+fn print_history(cfg: &ArgMatches<'_>, cargo_home: &Path) {
+    let records = read_history(cargo_home);
+    if cfg.is_present("failures") {
+        print_repeated_failures(&records);
+    } else if let Some(name) = cfg.value_of("crate") {
+        print_crate_history(&records, name);
+    } else {
+        print_runs(&records);
+    }
+}
+
+fn list(cfg: &ArgMatches<'_>) {
+    let install_roots = install_roots(cfg);
+    for install_root in &install_roots {
+        if install_roots.len() > 1 {
+            println!("  Install root '{}'", install_root.display());
+        }
+        print_crate_list(&installed_crates(install_root));
+    }
+}
+
+fn explain(cfg: &ArgMatches<'_>) {
+    let target = cfg.value_of("target").unwrap();
+    let rust_lib_path = get_rustc_lib_path();
+    let mut found = false;
+    for install_root in &install_roots(cfg) {
+        let packages = installed_crates(install_root)
+            .into_iter()
+            .filter(|package| {
+                package.name == target || package.binaries.iter().any(|bin| bin == target)
+            })
+            .collect::<Vec<_>>();
+        if !packages.is_empty() {
+            explain_crates(&packages, &rust_lib_path, install_root);
+            found = true;
+        }
+    }
+    if !found {
+        eprintln!("No installed crate or binary named '{}'", target);
+        std::process::exit(3);
+    }
+}
+
+fn who_needs(cfg: &ArgMatches<'_>) {
+    let library = cfg.value_of("library").unwrap();
+    let query = LibraryQuery::new(library);
+    let rust_lib_path = get_rustc_lib_path();
+    let install_roots = install_roots(cfg);
+    let mut dependents = Vec::new();
+    for install_root in &install_roots {
+        if install_roots.len() > 1 {
+            println!("  Install root '{}'", install_root.display());
+        }
+        let packages = installed_crates(install_root);
+        dependents.extend(print_dependents(
+            &packages,
+            install_root,
+            &rust_lib_path,
+            &query,
+        ));
+    }
+    if dependents.is_empty() {
+        println!("  No installed crate needs '{}'", library);
+    } else {
+        println!("\n  Crates needing '{}': {}", library, dependents.join(" "));
+    }
+}
+
+fn toolchains(cfg: &ArgMatches<'_>) {
+    let toolchains = match get_rustup_toolchains_dir() {
+        Some(toolchains_dir) => installed_toolchains(&toolchains_dir),
+        None => Vec::new(),
+    };
+    if toolchains.is_empty() {
+        eprintln!("No rustup toolchains found");
+        std::process::exit(3);
+    }
+    let rust_lib_path = get_rustc_lib_path();
+    let mut users = Vec::new();
+    for install_root in &install_roots(cfg) {
+        let packages = installed_crates(install_root);
+        users.extend(rust_library_users(
+            &packages,
+            &install_root.join("bin"),
+            &rust_lib_path,
+        ));
+    }
+    let unused = print_toolchain_users(&toolchains, &users, &rust_lib_path, &pinned_toolchains());
+    if unused.is_empty() {
+        println!("\n  Every toolchain is needed by an installed binary.");
+    } else {
+        println!("\n  Safe to remove: {}", unused.join(" "));
+    }
+}
+
+// check what a change of the rustup toolchains would break, without recording anything
+fn simulate(cfg: &ArgMatches<'_>, cargo_home: &Path, fail_if_broken: bool) {
+    let rust_lib_path = get_rustc_lib_path();
+    let (name, removing) = match cfg.value_of("simulate-remove-toolchain") {
+        Some(name) => (name, true),
+        None => (cfg.value_of("simulate-default").unwrap(), false),
+    };
+    let toolchain = match find_toolchain(name) {
+        Some(toolchain) => toolchain,
+        None => {
+            eprintln!("No rustup toolchain '{}' installed", name);
             std::process::exit(3);
-        },
+        }
+    };
+    let toolchain_name = toolchain
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    let (simulated_lib_path, removed_lib_dir) = if removing {
+        println!("  Simulating the removal of toolchain '{}'", toolchain_name);
+        // the sysroot is gone as well if it is the default toolchain
+        let lib_path = if Path::new(&rust_lib_path).starts_with(&toolchain) {
+            String::new()
+        } else {
+            rust_lib_path.clone()
+        };
+        (lib_path, Some(toolchain))
+    } else {
+        println!("  Simulating '{}' as the default toolchain", toolchain_name);
+        (toolchain.join("lib").display().to_string(), None)
     };
 
+    let cache = load_cache(cfg, cargo_home, &rust_lib_path);
+    let options = CheckOptions {
+        deep: cfg.is_present("deep"),
+        verbose: cfg.is_present("verbose"),
+        print_order: Some(sort_order(cfg)),
+        removed_lib_dir,
+        ..CheckOptions::default()
+    };
+
+    let mut would_break = Vec::new();
+    let mut fixed = Vec::new();
+    for install_root in &install_roots(cfg) {
+        let packages = installed_crates(install_root);
+        let (broken, working) = simulate_crates(
+            &packages,
+            &rust_lib_path,
+            &simulated_lib_path,
+            &install_root.join("bin"),
+            cache.as_ref(),
+            &options,
+        );
+        would_break.extend(broken.iter().map(|pkg| pkg.name.clone()));
+        fixed.extend(working.iter().map(|pkg| pkg.name.clone()));
+    }
+    if would_break.is_empty() {
+        println!("\n  Nothing would break.");
+    } else {
+        println!("\n  Crates that would break: {}", would_break.join(" "));
+    }
+    if !fixed.is_empty() {
+        println!("  Crates that would be fixed: {}", fixed.join(" "));
+    }
+
+    if let Some(cache) = cache {
+        cache.save();
+    }
+    if fail_if_broken && !would_break.is_empty() {
+        std::process::exit(5);
+    }
+}
+
+// check (and maybe rebuild) the crates of every install root
+fn check(cfg: &ArgMatches<'_>, cargo_home: &Path, do_auto_rebuild: bool, fail_if_broken: bool) {
+    if cfg.is_present("simulate-remove-toolchain") || cfg.is_present("simulate-default") {
+        simulate(cfg, cargo_home, fail_if_broken);
+        return;
+    }
+    let install_roots = install_roots(cfg);
+
+    // "rebuild <crates>" reinstalls exactly these, a typo must not leave a half done rebuild
+    let selected: Vec<&str> = cfg
+        .values_of("crates")
+        .map(|crates| crates.collect())
+        .unwrap_or_default();
+    let mut roots = Vec::new();
+    let mut unknown_crates = selected.clone();
+    for install_root in &install_roots {
+        let mut packages = installed_crates(install_root);
+        if !selected.is_empty() {
+            packages.retain(|package| selected.contains(&package.name.as_str()));
+            unknown_crates.retain(|name| !packages.iter().any(|package| package.name == *name));
+            if packages.is_empty() {
+                continue;
+            }
+        }
+        roots.push((install_root, packages));
+    }
+    if !unknown_crates.is_empty() {
+        eprintln!("Not installed: {}", unknown_crates.join(" "));
+        std::process::exit(3);
+    }
+
     // get the path where rustc libs are stored: $(rustc --print sysroot)/lib
     let rust_lib_path = get_rustc_lib_path();
 
-    check_and_rebuild_broken_crates(
-        &packages,
-        &rust_lib_path,
-        &bin_dir,
-        cfg.is_present("auto-rebuild"),
-        cfg.is_present("rebuild-all"),
-    )
+    let cache = load_cache(cfg, cargo_home, &rust_lib_path);
+
+    // cron jobs and shell hooks only want to hear about changes
+    let diff_mode = cfg.is_present("diff");
+    let previous_run = if diff_mode {
+        read_baseline(cargo_home, &install_roots)
+    } else {
+        None
+    };
+
+    let fix = if do_auto_rebuild {
+        match cfg.value_of("fix") {
+            Some("relink") => Some(FixStrategy::Relink),
+            Some("wrapper") => Some(FixStrategy::Wrapper),
+            _ => Some(FixStrategy::Rebuild),
+        }
+    } else {
+        None
+    };
+    let rebuild_all = cfg.is_present("rebuild-all") || !selected.is_empty();
+    let options = CheckOptions {
+        rebuild_all,
+        print_order: if diff_mode {
+            None
+        } else {
+            Some(sort_order(cfg))
+        },
+        ..check_options(cfg)
+    };
+    if fix.is_some() || rebuild_all {
+        check_cargo_flags(&options.cargo_flags);
+    }
+
+    let kind = if fix.is_some() || rebuild_all {
+        RunKind::Rebuild
+    } else {
+        RunKind::Check
+    };
+    let mut history = HistoryRecord::new(kind, get_rustc_version(), &rust_lib_path);
+    let mut all_rebuilds_succeeded = true;
+    for (install_root, packages) in &roots {
+        if install_roots.len() > 1 && !diff_mode {
+            println!("  Install root '{}'", install_root.display());
+        }
+
+        all_rebuilds_succeeded &= check_and_rebuild_broken_crates(
+            packages,
+            &rust_lib_path,
+            install_root,
+            cache.as_ref(),
+            &mut history,
+            fix,
+            &options,
+        );
+        if selected.is_empty() {
+            history.add_root(install_root);
+        }
+    }
+
+    if diff_mode {
+        print_changes(previous_run.as_ref(), &history);
+    }
+    history.append_to(cargo_home);
+
+    if let Some(cache) = cache {
+        cache.save();
+    }
+
+    if !all_rebuilds_succeeded {
+        std::process::exit(4);
+    }
+    if fail_if_broken && history.has_broken_crates() {
+        std::process::exit(5);
+    }
+}
+
+fn main() {
+    let cfg = gen_clap();
+    // we need this in case we call "cargo-rebuild-check" directly
+    let cfg = cfg.subcommand_matches("rebuild-check").unwrap_or(&cfg);
+
+    // the doctor also looks into why programs are missing
+    if cfg.subcommand_matches("doctor").is_some() {
+        if diagnose() > 0 {
+            std::process::exit(3);
+        }
+        return;
+    }
+
+    match all_binaries_available() {
+        Ok(_) => {}
+        Err(missing_bins) => {
+            eprintln!("Could not find the following binaries: '{}'", missing_bins);
+            eprintln!("Please make them available in your $PATH.");
+            std::process::exit(1);
+        }
+    }
+
+    let cargo_home = exit_on_error(get_cargo_home());
+
+    match cfg.subcommand() {
+        ("check", Some(check_cfg)) => check(check_cfg, &cargo_home, false, true),
+        ("rebuild", Some(rebuild_cfg)) => check(rebuild_cfg, &cargo_home, true, false),
+        ("list", Some(list_cfg)) => list(list_cfg),
+        ("explain", Some(explain_cfg)) => explain(explain_cfg),
+        ("who-needs", Some(who_needs_cfg)) => who_needs(who_needs_cfg),
+        ("toolchains", Some(toolchains_cfg)) => toolchains(toolchains_cfg),
+        ("history", Some(history_cfg)) => print_history(history_cfg, &cargo_home),
+        ("watch", Some(watch_cfg)) => {
+            let do_auto_rebuild = watch_cfg.is_present("auto-rebuild");
+            let options = check_options(watch_cfg);
+            if do_auto_rebuild {
+                check_cargo_flags(&options.cargo_flags);
+            }
+            watch(
+                &install_roots(watch_cfg),
+                &cargo_home,
+                !watch_cfg.is_present("no-cache"),
+                &options,
+                do_auto_rebuild,
+            );
+        }
+        // no subcommand, the flags decide
+        _ => check(cfg, &cargo_home, cfg.is_present("auto-rebuild"), false),
+    }
 }
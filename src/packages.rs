@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// where rpm based distributions install libraries, relative to the root
+const RPM_LIB_DIRS: &[&str] = &["usr/lib64", "usr/lib", "lib64", "lib"];
+
+// the package manager whose local database we found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PackageDb {
+    Dpkg,
+    Pacman,
+    Rpm,
+}
+
+impl PackageDb {
+    // used when os-release doesn't name the distribution
+    fn distro(self) -> &'static str {
+        match self {
+            PackageDb::Dpkg => "Debian",
+            PackageDb::Pacman => "Arch Linux",
+            PackageDb::Rpm => "RPM",
+        }
+    }
+
+    // the package needed to link against a library, given the package shipping the library
+    fn dev_package(self, package: &str) -> String {
+        match self {
+            // libssl1.1 -> libssl-dev, libgit2-1.5 -> libgit2-dev
+            PackageDb::Dpkg => {
+                let mut base_len = package
+                    .rfind(|c: char| !c.is_ascii_digit() && c != '.')
+                    .map_or(0, |position| position + 1);
+                if package[..base_len].ends_with('-') {
+                    base_len -= 1;
+                }
+                format!("{}-dev", &package[..base_len])
+            }
+            // headers are part of the package itself
+            PackageDb::Pacman => package.to_string(),
+            // openssl-libs -> openssl-devel
+            PackageDb::Rpm => {
+                let base_len = if package.ends_with("-libs") {
+                    package.len() - 5
+                } else {
+                    package.len()
+                };
+                format!("{}-devel", &package[..base_len])
+            }
+        }
+    }
+}
+
+// the installed files we are interested in and the packages that own them, only read
+// from the local package database so this works offline
+pub(crate) struct PackageIndex {
+    db: PackageDb,
+    // the distribution the suggestions are for, like "Debian GNU/Linux"
+    distro: String,
+    // file name -> package
+    owners: HashMap<String, String>,
+}
+
+// NAME from os-release, a local file on every systemd era distribution
+fn os_release_name(root: &Path) -> Option<String> {
+    let content = fs::read_to_string(root.join("etc/os-release"))
+        .or_else(|_| fs::read_to_string(root.join("usr/lib/os-release")))
+        .ok()?;
+    let name = content
+        .lines()
+        .find(|line| line.starts_with("NAME="))?
+        .trim_start_matches("NAME=")
+        .trim_matches(|c| c == '"' || c == '\'');
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// "libssl.so.1.0.0" -> "libssl.so"
+fn link_name(library: &str) -> &str {
+    match library.find(".so") {
+        Some(position) => &library[..position + 3],
+        None => library,
+    }
+}
+
+impl PackageIndex {
+    // None if no package database we know of was found below root
+    pub(crate) fn load(root: &Path, libraries: &[&str]) -> Option<Self> {
+        let wanted = libraries
+            .iter()
+            .map(|library| link_name(library))
+            .collect::<Vec<_>>();
+        let is_wanted = |path: &str| -> Option<String> {
+            let file_name = Path::new(path).file_name()?.to_str()?;
+            if wanted.iter().any(|name| file_name.starts_with(name)) {
+                Some(file_name.to_string())
+            } else {
+                None
+            }
+        };
+
+        let mut owners = HashMap::new();
+        let db = if root.join("var/lib/dpkg/info").is_dir() {
+            for (package, files) in read_dpkg_lists(&root.join("var/lib/dpkg/info")) {
+                for file in files.lines().filter_map(|path| is_wanted(path)) {
+                    owners.insert(file, package.clone());
+                }
+            }
+            PackageDb::Dpkg
+        } else if root.join("var/lib/pacman/local").is_dir() {
+            for (package, files) in read_pacman_db(&root.join("var/lib/pacman/local")) {
+                for file in files.lines().filter_map(|path| is_wanted(path)) {
+                    owners.insert(file, package.clone());
+                }
+            }
+            PackageDb::Pacman
+        } else if root.join("var/lib/rpm").is_dir() {
+            owners.extend(read_rpm_owners(root, &is_wanted));
+            PackageDb::Rpm
+        } else {
+            return None;
+        };
+
+        let distro = os_release_name(root).unwrap_or_else(|| db.distro().to_string());
+        Some(Self { db, distro, owners })
+    }
+
+    // "install libssl-dev (Debian)"
+    pub(crate) fn suggestion(&self, library: &str) -> Option<String> {
+        let link = link_name(library);
+        let package = match self.owners.get(link) {
+            // the development package is still around, installing it again pulls in the
+            // library the crate gets linked against
+            Some(dev_package) => dev_package.clone(),
+            None => {
+                // the library itself or another version of it, like libssl.so.1.1 for libssl.so.1.0.0
+                let mut candidates = self
+                    .owners
+                    .iter()
+                    .filter(|(file, _)| {
+                        file.as_str() == library || file.starts_with(&format!("{}.", link))
+                    })
+                    .map(|(_, package)| package)
+                    .collect::<Vec<_>>();
+                candidates.sort();
+                self.db.dev_package(candidates.first()?)
+            }
+        };
+        Some(format!("install {} ({})", package, self.distro))
+    }
+}
+
+fn read_dpkg_lists(info_dir: &Path) -> Vec<(String, String)> {
+    // info/<package>[:<arch>].list contains one installed path per line
+    let entries = match fs::read_dir(info_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension() == Some(OsStr::new("list")))
+        .filter_map(|path| {
+            let stem = path.file_stem()?.to_str()?;
+            let package = stem.split(':').next()?.to_string();
+            let files = fs::read_to_string(&path).ok()?;
+            Some((package, files))
+        })
+        .collect()
+}
+
+fn read_pacman_db(local_dir: &Path) -> Vec<(String, String)> {
+    // local/<package>-<version>-<release>/desc has the name after %NAME%,
+    // local/<package>-<version>-<release>/files lists paths relative to / after %FILES%
+    let entries = match fs::read_dir(local_dir) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter_map(|dir: PathBuf| {
+            let desc = fs::read_to_string(dir.join("desc")).ok()?;
+            let package = desc
+                .lines()
+                .skip_while(|line| *line != "%NAME%")
+                .nth(1)?
+                .to_string();
+            let files = fs::read_to_string(dir.join("files")).ok()?;
+            Some((package, files))
+        })
+        .collect()
+}
+
+fn read_rpm_owners<F>(root: &Path, is_wanted: &F) -> Vec<(String, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    // the rpmdb is a binary database and listing every file in it is slow, so only ask rpm
+    // about the wanted files in the library dirs
+    let mut owners = Vec::new();
+    for dir in RPM_LIB_DIRS {
+        let entries = match fs::read_dir(root.join(dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = format!("/{}/{}", dir, entry.file_name().to_string_lossy());
+            let file = match is_wanted(&path) {
+                Some(file) => file,
+                None => continue,
+            };
+            let output = Command::new("rpm")
+                .arg("--root")
+                .arg(root)
+                .arg("-qf")
+                .arg("--queryformat")
+                .arg("%{NAME}\n")
+                .arg(&path)
+                .output();
+            match output {
+                Ok(ref out) if out.status.success() => {
+                    let package = String::from_utf8_lossy(&out.stdout);
+                    if let Some(package) = package.lines().next() {
+                        owners.push((file, package.to_string()));
+                    }
+                }
+                // not owned by any package
+                Ok(_) => {}
+                // no rpm installed, nothing we can tell
+                Err(_) => return owners,
+            }
+        }
+    }
+    owners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use std::fs::File;
+    use std::io::prelude::*;
+
+    fn write_file(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut file = File::create(path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn dpkg_suggestions() {
+        let root = TempDir::new("dpkg");
+        let info = root.path().join("var/lib/dpkg/info");
+        write_file(
+            &info.join("libssl1.1:amd64.list"),
+            "/.\n/usr/lib/x86_64-linux-gnu/libssl.so.1.1\n",
+        );
+        write_file(
+            &info.join("libgit2-dev:amd64.list"),
+            "/usr/lib/x86_64-linux-gnu/libgit2.so\n/usr/include/git2.h\n",
+        );
+        write_file(
+            &info.join("libgit2-28:amd64.list"),
+            "/usr/lib/libgit2.so.28\n",
+        );
+
+        let index = PackageIndex::load(
+            root.path(),
+            &["libssl.so.1.0.0", "libgit2.so.26", "libfoo.so.1"],
+        )
+        .unwrap();
+        assert_eq!(
+            index.suggestion("libssl.so.1.0.0"),
+            Some(String::from("install libssl-dev (Debian)"))
+        );
+        assert_eq!(
+            index.suggestion("libgit2.so.26"),
+            Some(String::from("install libgit2-dev (Debian)"))
+        );
+        assert_eq!(index.suggestion("libfoo.so.1"), None);
+    }
+
+    #[test]
+    fn pacman_suggestions() {
+        let root = TempDir::new("pacman");
+        let package_dir = root.path().join("var/lib/pacman/local/openssl-1.1.0.h-1");
+        write_file(
+            &package_dir.join("desc"),
+            "%NAME%\nopenssl\n\n%VERSION%\n1.1.0.h-1\n",
+        );
+        write_file(
+            &package_dir.join("files"),
+            "%FILES%\nusr/\nusr/lib/\nusr/lib/libssl.so\nusr/lib/libssl.so.1.1\n",
+        );
+
+        let index = PackageIndex::load(root.path(), &["libssl.so.1.0.0"]).unwrap();
+        assert_eq!(
+            index.suggestion("libssl.so.1.0.0"),
+            Some(String::from("install openssl (Arch Linux)"))
+        );
+    }
+
+    #[test]
+    fn os_release_names_distribution() {
+        let root = TempDir::new("os_release");
+        write_file(
+            &root.path().join("var/lib/dpkg/info/libssl1.1:amd64.list"),
+            "/usr/lib/x86_64-linux-gnu/libssl.so.1.1\n",
+        );
+        write_file(
+            &root.path().join("etc/os-release"),
+            "PRETTY_NAME=\"Ubuntu 18.04.1 LTS\"\nNAME=\"Ubuntu\"\nVERSION_ID=\"18.04\"\n",
+        );
+
+        let index = PackageIndex::load(root.path(), &["libssl.so.1.0.0"]).unwrap();
+        assert_eq!(
+            index.suggestion("libssl.so.1.0.0"),
+            Some(String::from("install libssl-dev (Ubuntu)"))
+        );
+    }
+
+    #[test]
+    fn no_package_db() {
+        let root = TempDir::new("no_package_db");
+        assert!(PackageIndex::load(root.path(), &["libssl.so.1.0.0"]).is_none());
+    }
+}
@@ -1,11 +1,15 @@
 #[cfg(test)]
 use test::*;
 
+use std::env;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::{Path, PathBuf};
 
 use crate::errors::*;
 
+pub(crate) const CRATES_IO_INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
 // a package that we may need to rebuild
 #[derive(Debug)]
 pub(crate) struct CrateInfo {
@@ -15,12 +19,67 @@ pub(crate) struct CrateInfo {
     pub(crate) branch: Option<String>,
     pub(crate) tag: Option<String>,
     pub(crate) rev: Option<String>,
+    // the commit a git crate was built from, whatever branch, tag or rev it was installed with
+    pub(crate) commit: Option<String>,
     pub(crate) registry: Option<String>,
     pub(crate) path: Option<String>,
     pub(crate) binaries: Vec<String>,
 }
 
-pub(crate) fn read_crates_toml() -> Result<String, ErrorKind> {
+impl CrateInfo {
+    // where "cargo install" got the crate from, "crates.io" or "git https://github.com/... (tag 0.8.0)"
+    pub(crate) fn source(&self) -> String {
+        if let Some(ref git) = self.git {
+            let pinned = match (&self.branch, &self.tag, &self.rev) {
+                (Some(branch), _, _) => format!(" (branch {})", branch),
+                (_, Some(tag), _) => format!(" (tag {})", tag),
+                (_, _, Some(rev)) => format!(" (rev {})", rev),
+                _ => String::new(),
+            };
+            format!("git {}{}", git, pinned)
+        } else if let Some(ref path) = self.path {
+            format!("path {}", path)
+        } else {
+            match self.registry {
+                Some(ref registry) if registry == CRATES_IO_INDEX => String::from("crates.io"),
+                Some(ref registry) => format!("registry {}", registry),
+                None => String::from("unknown"),
+            }
+        }
+    }
+}
+
+pub(crate) fn get_cargo_home() -> Result<PathBuf, ErrorKind> {
+    match cargo::util::config::Config::default() {
+        Ok(cargo_cfg) => Ok(cargo_cfg.home().clone().into_path_unlocked()),
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(ErrorKind::NoCargoHome)
+        }
+    }
+}
+
+// the name of an alternative registry in the cargo config, "cargo install" only takes that
+pub(crate) fn registry_name(index: &str) -> Option<String> {
+    let cargo_cfg = cargo::util::config::Config::default().ok()?;
+    let registries = cargo_cfg.get_table("registries").ok()??;
+    let mut names = registries.val.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().find(|name| {
+        cargo_cfg
+            .get_registry_index(name)
+            .map(|url| url.as_str().trim_end_matches('/') == index.trim_end_matches('/'))
+            .unwrap_or(false)
+    })
+}
+
+pub(crate) fn get_install_roots(explicit_roots: Vec<PathBuf>) -> Result<Vec<PathBuf>, ErrorKind> {
+    // every install root has its own bin/ dir and .crates.toml
+    // if the user told us which roots to check, use only these
+    if !explicit_roots.is_empty() {
+        return Ok(explicit_roots);
+    }
+
     let cargo_cfg = match cargo::util::config::Config::default() {
         Ok(cargo_cfg) => cargo_cfg,
         Err(e) => {
@@ -28,13 +87,42 @@ pub(crate) fn read_crates_toml() -> Result<String, ErrorKind> {
             return Err(ErrorKind::NoCargoHome);
         }
     };
+    let cargo_home = cargo_cfg.home().clone().into_path_unlocked();
 
-    let mut crates_index = cargo_cfg.home().clone();
-    crates_index.push(".crates.toml");
-    let crates_toml_path = crates_index.into_path_unlocked();
+    // same precedence as "cargo install": $CARGO_INSTALL_ROOT, then install.root from the config
+    let configured_root = match env::var_os("CARGO_INSTALL_ROOT") {
+        Some(root) => Some(PathBuf::from(root)),
+        None => match cargo_cfg.get_path("install.root") {
+            Ok(root) => root.map(|value| value.val),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        },
+    };
+
+    let mut roots = Vec::new();
+    if let Some(root) = configured_root {
+        roots.push(root);
+    }
+    // crates installed before the root was configured are still in $CARGO_HOME
+    if !roots.contains(&cargo_home) {
+        roots.push(cargo_home.clone());
+    }
+    // don't bother with roots nothing has been installed into
+    roots.retain(|root| root.join(".crates.toml").is_file());
+    if roots.is_empty() {
+        // let read_crates_toml() complain about it
+        roots.push(cargo_home);
+    }
+    Ok(roots)
+}
+
+pub(crate) fn read_crates_toml(install_root: &Path) -> Result<String, ErrorKind> {
+    let crates_toml_path = install_root.join(".crates.toml");
 
     if !crates_toml_path.is_file() {
-        eprintln!("No .crates.toml");
+        eprintln!("No .crates.toml found in '{}'", install_root.display());
         return Err(ErrorKind::NoCratesToml);
     }
 
@@ -60,7 +148,7 @@ pub(crate) fn read_crates_toml() -> Result<String, ErrorKind> {
 pub(crate) fn get_installed_crate_information(
     file_content: Result<String, ErrorKind>,
 ) -> Result<Vec<CrateInfo>, ErrorKind> {
-    let file = file_content.unwrap();
+    let file = file_content?;
 
     let mut file_lines = file.lines();
     // skip the first line when unwrapping
@@ -85,6 +173,7 @@ pub(crate) fn decode_line(line: &str) -> self::CrateInfo {
         branch: None,
         tag: None,
         rev: None,
+        commit: None,
         registry: None,
         path: None,
         binaries: Vec::new(),
@@ -111,7 +200,7 @@ pub(crate) fn decode_line(line: &str) -> self::CrateInfo {
             // cargo-rebuild-check v0.1.0 (https://github.com/matthiaskrgr/cargo-rebuild-check#2ce1ed0b):
             let mut split = addr.split('#');
             let repo = split.next().unwrap();
-            // rev does not matter unless we have "?rev="
+            // the part after "#" is the commit that was built, "?rev=" is what was asked for
             // cargo-update v1.4.1 (https://github.com/nabijaczleweli/cargo-update/?rev=ab82e070aaf4755fc38d15ca7d58acf4b697731d#ab82e070):
 
             let has_explicit_rev: bool = repo.contains("?rev=");
@@ -145,6 +234,10 @@ pub(crate) fn decode_line(line: &str) -> self::CrateInfo {
             }
             let repo_url = repo.split('?').nth(0).unwrap();
             package.git = Some(repo_url.to_string());
+            package.commit = split
+                .next()
+                .filter(|commit| !commit.is_empty())
+                .map(String::from);
         }
         Some("path") => {
             // try to make the path absolute (file:///home/....  -> /home/....)
@@ -206,6 +299,10 @@ mod tests {
         assert_eq!(ci.branch, None);
         assert_eq!(ci.tag, None);
         assert_eq!(ci.rev, None);
+        assert_eq!(
+            ci.commit,
+            Some("6083f409343aeb8c7fcedd1877fd1ae4ef8c9e49".to_string())
+        );
         assert_eq!(ci.registry, None);
         assert_eq!(ci.path, None);
         assert_eq!(ci.binaries, vec!["cargo-cache"]);
@@ -224,6 +321,10 @@ mod tests {
         assert_eq!(ci.branch, Some("scrollback".to_string()));
         assert_eq!(ci.tag, None);
         assert_eq!(ci.rev, None);
+        assert_eq!(
+            ci.commit,
+            Some("9ee1cf2455d5512b087757e09451f9d122548da2".to_string())
+        );
         assert_eq!(ci.registry, None);
         assert_eq!(ci.path, None);
         assert_eq!(ci.binaries, vec!["alacritty"]);
@@ -295,6 +396,7 @@ mod tests {
         assert_eq!(ci.branch, None);
         assert_eq!(ci.tag, None);
         assert_eq!(ci.rev, None);
+        assert_eq!(ci.commit, None);
         assert_eq!(ci.registry, None);
         assert_eq!(ci.path, Some("/tmp/racer".to_string()));
         assert_eq!(ci.binaries, vec!["racer"]);
@@ -321,6 +423,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn crate_sources() {
+        let lines = [
+            ("\"mdbook 0.1.5 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"mdbook\"]", "crates.io"),
+            ("\"foo 0.1.0 (registry+https://example.com/index)\" = [\"foo\"]", "registry https://example.com/index"),
+            ("\"ripgrep 0.8.0 (git+https://github.com/BurntSushi/ripgrep?tag=0.8.0#23d1b91eaddbfb886a3a99d615f49551cd35cb6c)\" = [\"rg\"]", "git https://github.com/BurntSushi/ripgrep (tag 0.8.0)"),
+            ("\"cargo-cache 0.1.0 (git+http://github.com/matthiaskrgr/cargo-cache#6083f409343aeb8c7fcedd1877fd1ae4ef8c9e49)\" = [\"cargo-cache\"]", "git http://github.com/matthiaskrgr/cargo-cache"),
+            ("\"racer 2.0.12 (path+file:///tmp/racer)\" = [\"racer\"]", "path /tmp/racer"),
+        ];
+        for (line, source) in &lines {
+            assert_eq!(decode_line(line).source(), *source);
+        }
+    }
+
     #[test]
     fn check_failure_on_invalid_api() {
         let file_content = "[v2]
@@ -0,0 +1,278 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::core::*;
+use crate::elf::*;
+use crate::errors::*;
+use crate::parse::*;
+use crate::script::*;
+use crate::toolchains::*;
+
+// the original binaries are kept here, inside the install root they came from
+pub(crate) const BACKUP_DIR: &str = ".rebuild-check-backup";
+
+// a binary that gets its libraries from other directories
+pub(crate) struct Relink {
+    pub(crate) binary: String,
+    pub(crate) path: PathBuf,
+    pub(crate) lib_dirs: Vec<PathBuf>,
+}
+
+// the toolchain lib dirs that have the missing libraries, None if some are in none of them
+fn toolchain_lib_dirs(missing: &[String], toolchains: &[PathBuf]) -> Option<Vec<PathBuf>> {
+    let mut lib_dirs: Vec<PathBuf> = Vec::new();
+    for library in missing {
+        let lib_dir = toolchains
+            .iter()
+            .map(|toolchain| toolchain.join("lib"))
+            .find(|lib_dir| lib_dir.join(library).is_file())?;
+        if !lib_dirs.contains(&lib_dir) {
+            lib_dirs.push(lib_dir);
+        }
+    }
+    Some(lib_dirs)
+}
+
+// the binaries of a crate that need relinking, None if the crate can't be fixed that way
+pub(crate) fn plan_relinks(
+    package: &CrateInfo,
+    bin_dir: &Path,
+    rustc_lib_path: &str,
+    toolchains: &[PathBuf],
+) -> Option<Vec<Relink>> {
+    let mut relinks = Vec::new();
+    for binary in &package.binaries {
+        let path = bin_dir.join(binary);
+        // there is nothing to relink if the binary is gone
+        if !path.is_file() {
+            return None;
+        }
+        if read_shebang(&path).is_some() {
+            continue;
+        }
+        let missing = resolve_libraries(&path, rustc_lib_path)
+            .into_iter()
+            .filter(|(_, found)| found.is_none())
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            continue;
+        }
+        relinks.push(Relink {
+            binary: binary.clone(),
+            lib_dirs: toolchain_lib_dirs(&missing, toolchains)?,
+            path,
+        });
+    }
+    if relinks.is_empty() {
+        None
+    } else {
+        Some(relinks)
+    }
+}
+
+// write the file next to the old one and move it over it, a running binary can't be written to
+fn replace_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".rebuild-check-tmp");
+    let temporary = PathBuf::from(temporary);
+    fs::write(&temporary, data)?;
+    fs::set_permissions(&temporary, fs::metadata(path)?.permissions())?;
+    fs::rename(&temporary, path)
+}
+
+// keeps the directories the binary already searches, the toolchain lib dirs go first, returns
+// the binary as it was before
+fn relink_binary(relink: &Relink, backup: &Path) -> Result<Vec<u8>, ErrorKind> {
+    let original = fs::read(&relink.path).map_err(|_| ErrorKind::NotElf)?;
+    let mut elf = Elf::read(&relink.path)?;
+    let dynamic = elf.dynamic()?;
+    let old_dirs = if dynamic.runpath.is_empty() {
+        dynamic.rpath
+    } else {
+        dynamic.runpath
+    };
+    let mut runpath = relink
+        .lib_dirs
+        .iter()
+        .map(|lib_dir| lib_dir.display().to_string())
+        .collect::<Vec<_>>();
+    for dir in old_dirs {
+        if !runpath.contains(&dir) {
+            runpath.push(dir);
+        }
+    }
+    elf.set_runpath(&runpath.join(":"))?;
+
+    // a binary relinked before is backed up already, keep the binary cargo installed
+    if !backup.exists() {
+        fs::create_dir_all(backup.parent().unwrap()).map_err(|_| ErrorKind::NotWritten)?;
+        fs::copy(&relink.path, backup).map_err(|_| ErrorKind::NotWritten)?;
+    }
+    replace_file(&relink.path, elf.bytes()).map_err(|_| ErrorKind::NotWritten)?;
+    Ok(original)
+}
+
+fn restore_binary(relink: &Relink, original: &[u8]) {
+    if replace_file(&relink.path, original).is_err() {
+        eprintln!("    Could not restore '{}'", relink.path.display());
+    }
+}
+
+// a binary we relinked, it keeps its backup until a rebuild replaces it
+pub(crate) fn is_relinked(bin_dir: &Path, binary: &str) -> bool {
+    bin_dir.parent().map_or(false, |install_root| {
+        install_root.join(BACKUP_DIR).join(binary).is_file()
+    })
+}
+
+// a rebuild replaces the relinked binaries, their backups are of no use anymore
+pub(crate) fn remove_backups(package: &CrateInfo, install_root: &Path) {
+    let backup_dir = install_root.join(BACKUP_DIR);
+    for binary in &package.binaries {
+        let _ = fs::remove_file(backup_dir.join(binary));
+    }
+}
+
+// point the binaries of a crate at the toolchain that still has the libraries they miss,
+// returns false if that does not fix the crate, it is left as it was then
+fn relink_crate(
+    package: &CrateInfo,
+    install_root: &Path,
+    rustc_lib_path: &str,
+    toolchains: &[PathBuf],
+) -> bool {
+    let bin_dir = install_root.join("bin");
+    let relinks = match plan_relinks(package, &bin_dir, rustc_lib_path, toolchains) {
+        Some(relinks) => relinks,
+        None => return false,
+    };
+    println!("  Relinking {}", package.name);
+
+    let backup_dir = install_root.join(BACKUP_DIR);
+    let mut relinked = Vec::new();
+    let mut failure = None;
+    for relink in &relinks {
+        match relink_binary(relink, &backup_dir.join(&relink.binary)) {
+            Ok(original) => {
+                println!(
+                    "    Binary '{}' now loads libraries from {}",
+                    relink.binary,
+                    relink
+                        .lib_dirs
+                        .iter()
+                        .map(|lib_dir| lib_dir.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                relinked.push((relink, original));
+            }
+            Err(error) => {
+                failure = Some(format!(
+                    "could not relink binary '{}': {:?}",
+                    relink.binary, error
+                ));
+                break;
+            }
+        }
+    }
+    // the same check as before relinking
+    if failure.is_none()
+        && check_crate(
+            package,
+            &bin_dir,
+            rustc_lib_path,
+            None,
+            &CheckOptions::default(),
+        )
+        .broken
+    {
+        failure = Some(String::from("still broken after relinking"));
+    }
+
+    match failure {
+        Some(failure) => {
+            println!("    Relinking {} failed, {}", package.name, failure);
+            for (relink, original) in relinked {
+                restore_binary(relink, &original);
+            }
+            false
+        }
+        None => true,
+    }
+}
+
+pub(crate) fn rustup_toolchains() -> Vec<PathBuf> {
+    get_rustup_toolchains_dir()
+        .map(|toolchains_dir| installed_toolchains(&toolchains_dir))
+        .unwrap_or_default()
+}
+
+// relink what can be relinked, returns the crates that still need a rebuild
+pub(crate) fn relink_crates<'a>(
+    broken_pkgs: &[&'a CrateInfo],
+    install_root: &Path,
+    rustc_lib_path: &str,
+) -> Vec<&'a CrateInfo> {
+    let toolchains = rustup_toolchains();
+    broken_pkgs
+        .iter()
+        .filter(|package| !relink_crate(package, install_root, rustc_lib_path, &toolchains))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn relink_to_installed_toolchain() {
+        let cargo_home = FakeCargoHome::new("relink")
+            .broken_crate("pinned", "0.1.0", "libstd-0123456789abcdef.so")
+            .toolchain_library("old-x86_64-unknown-linux-gnu", "libstd-0123456789abcdef.so")
+            .working_crate("fine", "1.0.0")
+            .with_rustup_env()
+            .build();
+        let lib_dir = cargo_home.toolchain_lib_dir("old-x86_64-unknown-linux-gnu");
+        let binary = cargo_home.bin_dir().join("pinned");
+        let installed = fs::read(&binary).unwrap();
+
+        let output = cargo_home.run(&["rebuild", "--fix", "relink", "--full"]);
+        assert!(output.status.success());
+        let stdout = &output.stdout;
+        assert!(stdout.ends_with(&format!(
+            "\n  Crates needing rebuild: pinned
+  Relinking pinned
+    Binary 'pinned' now loads libraries from {}\n",
+            lib_dir.display()
+        )));
+        let backup = cargo_home.path().join(".rebuild-check-backup/pinned");
+        assert_eq!(fs::read(&backup).unwrap(), installed);
+
+        // relinking it once more must not replace the binary cargo installed with a relinked one
+        let relink = Relink {
+            binary: String::from("pinned"),
+            path: binary.clone(),
+            lib_dirs: vec![cargo_home.path().join("build")],
+        };
+        let relinked = relink_binary(&relink, &backup).unwrap();
+        assert_ne!(relinked, installed);
+        assert_eq!(fs::read(&backup).unwrap(), installed);
+        restore_binary(&relink, &relinked);
+
+        let output = cargo_home.run(&["check", "--full"]);
+        assert!(output.status.success());
+        assert!(output.stdout.ends_with("\n  Everything looks good! :)\n"));
+
+        // the toolchain it was relinked to goes away, a cached result must not hide that
+        fs::remove_dir_all(lib_dir.parent().unwrap()).unwrap();
+        let output = cargo_home.run(&["check", "--full"]);
+        assert_eq!(output.status.code(), Some(5));
+        assert!(output
+            .stdout
+            .ends_with("\n  Crates needing rebuild: pinned\n"));
+    }
+}
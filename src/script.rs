@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use crate::check_external_cmds::*;
+
+// the "#!" line of a script, for example "#!/usr/bin/env python3"
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Shebang {
+    pub(crate) interpreter: String,
+    // the kernel passes everything after the interpreter as a single argument
+    pub(crate) argument: Option<String>,
+}
+
+// how the interpreter of a script is broken
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum InterpreterProblem {
+    Missing(String),
+    NotExecutable(String),
+    // "#!/usr/bin/env <program>" and <program> is nowhere in PATH
+    NotInPath(String),
+    // the kernel looks for "#!bin/python" in the directory the script is run from
+    Relative(String),
+}
+
+impl InterpreterProblem {
+    pub(crate) fn describe(&self, script: &str) -> String {
+        match self {
+            InterpreterProblem::Missing(interpreter) => format!(
+                "Script '{}' needs the interpreter '{}', which does not exist",
+                script, interpreter
+            ),
+            InterpreterProblem::NotExecutable(interpreter) => format!(
+                "Script '{}' needs the interpreter '{}', which is not executable",
+                script, interpreter
+            ),
+            InterpreterProblem::NotInPath(program) => format!(
+                "Script '{}' runs '{}', which is not in PATH",
+                script, program
+            ),
+            InterpreterProblem::Relative(interpreter) => format!(
+                "Script '{}' needs the interpreter '{}', a relative path that only works from some \
+                 directories",
+                script, interpreter
+            ),
+        }
+    }
+}
+
+// None if the file is not a script (ELF binaries start with "\x7fELF")
+pub(crate) fn read_shebang(path: &Path) -> Option<Shebang> {
+    let mut file = File::open(path).ok()?;
+    // linux only looks at the first 128 bytes, so do we
+    let mut head = [0u8; 128];
+    let mut len = 0;
+    while len < head.len() {
+        match file.read(&mut head[len..]) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => len += read,
+        }
+    }
+    if !head[..len].starts_with(b"#!") {
+        return None;
+    }
+    let line = String::from_utf8_lossy(&head[2..len]);
+    let line = line.lines().next().unwrap_or("").trim();
+    let mut parts = line.splitn(2, |c: char| c == ' ' || c == '\t');
+    let interpreter = parts.next().unwrap_or("").to_string();
+    let argument = parts
+        .next()
+        .map(str::trim)
+        .filter(|argument| !argument.is_empty())
+        .map(String::from);
+    Some(Shebang {
+        interpreter,
+        argument,
+    })
+}
+
+impl Shebang {
+    pub(crate) fn problem(&self) -> Option<InterpreterProblem> {
+        let interpreter = Path::new(&self.interpreter);
+        if interpreter.is_relative() {
+            return Some(InterpreterProblem::Relative(self.interpreter.clone()));
+        }
+        if !interpreter.exists() {
+            return Some(InterpreterProblem::Missing(self.interpreter.clone()));
+        }
+        if !is_executable(interpreter) {
+            return Some(InterpreterProblem::NotExecutable(self.interpreter.clone()));
+        }
+        // "env python3" or "env -S python3 -u", the first word that is not an option
+        if interpreter.file_name().and_then(|name| name.to_str()) == Some("env") {
+            let program = self
+                .argument
+                .as_ref()
+                .and_then(|argument| {
+                    argument
+                        .split_whitespace()
+                        .find(|word| !word.starts_with('-') && !word.contains('='))
+                })
+                .map(String::from);
+            if let Some(program) = program {
+                if find_in_path(&program).is_none() {
+                    return Some(InterpreterProblem::NotInPath(program));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn shebang_lines() {
+        let cargo_home = FakeCargoHome::new("shebang_lines")
+            .script("plain", "#!/bin/sh\necho hi\n")
+            .script("env", "#!/usr/bin/env  python3 -u\n")
+            .script("no_newline", "#!/bin/sh")
+            .script("text", "echo hi\n")
+            .build();
+        let bin_dir = cargo_home.path().join("bin");
+
+        assert_eq!(
+            read_shebang(&bin_dir.join("plain")),
+            Some(Shebang {
+                interpreter: String::from("/bin/sh"),
+                argument: None,
+            })
+        );
+        assert_eq!(
+            read_shebang(&bin_dir.join("env")),
+            Some(Shebang {
+                interpreter: String::from("/usr/bin/env"),
+                argument: Some(String::from("python3 -u")),
+            })
+        );
+        assert_eq!(
+            read_shebang(&bin_dir.join("no_newline"))
+                .unwrap()
+                .interpreter,
+            "/bin/sh"
+        );
+        assert_eq!(read_shebang(&bin_dir.join("text")), None);
+        assert_eq!(read_shebang(&bin_dir.join("does_not_exist")), None);
+    }
+
+    #[test]
+    fn interpreter_problems() {
+        let shebang = |interpreter: &str, argument: Option<&str>| Shebang {
+            interpreter: interpreter.to_string(),
+            argument: argument.map(String::from),
+        };
+        assert_eq!(shebang("/bin/sh", None).problem(), None);
+        assert_eq!(shebang("/usr/bin/env", Some("sh")).problem(), None);
+        assert_eq!(
+            shebang("/does/not/exist", None).problem(),
+            Some(InterpreterProblem::Missing(String::from("/does/not/exist")))
+        );
+        assert_eq!(
+            shebang("/etc/passwd", None).problem(),
+            Some(InterpreterProblem::NotExecutable(String::from(
+                "/etc/passwd"
+            )))
+        );
+        assert_eq!(
+            shebang("../lib/venv/bin/python", None).problem(),
+            Some(InterpreterProblem::Relative(String::from(
+                "../lib/venv/bin/python"
+            )))
+        );
+        assert_eq!(
+            shebang("/usr/bin/env", Some("-S rebuild-check-no-such-program -x")).problem(),
+            Some(InterpreterProblem::NotInPath(String::from(
+                "rebuild-check-no-such-program"
+            )))
+        );
+    }
+}
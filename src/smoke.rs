@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::Read;
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long a binary may take to print its version
+const SMOKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// how a binary that links fine fails when it is run
+pub(crate) enum SmokeFailure {
+    Status(i32, String),
+    Signal(i32, String),
+    Timeout,
+    NotStarted(String),
+}
+
+impl SmokeFailure {
+    pub(crate) fn describe(&self, binary: &str, args: &[String]) -> String {
+        let command = if args.is_empty() {
+            format!("'{}'", binary)
+        } else {
+            format!("'{} {}'", binary, args.join(" "))
+        };
+        let (what, stderr) = match self {
+            SmokeFailure::Status(code, stderr) => (format!("exits with status {}", code), stderr),
+            SmokeFailure::Signal(signal, stderr) => {
+                (format!("is killed by signal {}", signal), stderr)
+            }
+            SmokeFailure::Timeout => {
+                return format!(
+                    "Binary '{}' fails at runtime: {} does not exit within {} seconds",
+                    binary,
+                    command,
+                    SMOKE_TIMEOUT.as_secs()
+                )
+            }
+            SmokeFailure::NotStarted(error) => {
+                return format!("Binary '{}' can't be started: {}", binary, error)
+            }
+        };
+        // the first line of a panic usually tells what is wrong
+        match stderr.lines().find(|line| !line.trim().is_empty()) {
+            Some(line) => format!(
+                "Binary '{}' fails at runtime: {} {}\n\t\t{}",
+                binary,
+                command,
+                what,
+                line.trim()
+            ),
+            None => format!("Binary '{}' fails at runtime: {} {}", binary, command, what),
+        }
+    }
+}
+
+// [rebuild-check.smoke] in the cargo config, read once before the crates get checked
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SmokeConfig {
+    // crate name -> arguments that replace "--version", "" skips the crate
+    args: HashMap<String, String>,
+}
+
+impl SmokeConfig {
+    pub(crate) fn load() -> Self {
+        let table = cargo::util::config::Config::default()
+            .ok()
+            .and_then(|cargo_cfg| cargo_cfg.get_table("rebuild-check.smoke").ok())
+            .and_then(|table| table.map(|table| table.val))
+            .unwrap_or_default();
+        let args = table
+            .into_iter()
+            .filter_map(|(name, value)| match value {
+                cargo::util::config::ConfigValue::String(args, _) => Some((name, args)),
+                _ => None,
+            })
+            .collect();
+        SmokeConfig { args }
+    }
+
+    // the arguments the binaries of a crate get run with, None if the crate is not run at all
+    pub(crate) fn args(&self, crate_name: &str) -> Option<Vec<String>> {
+        match self.args.get(crate_name) {
+            Some(args) if args.trim().is_empty() => None,
+            Some(args) => Some(args.split_whitespace().map(String::from).collect()),
+            None => Some(vec![String::from("--version")]),
+        }
+    }
+}
+
+// run the binary with nothing but PATH, HOME and the rustc libs, and without input
+pub(crate) fn smoke_test(
+    binary_path: &Path,
+    args: &[String],
+    rustc_lib_path: &str,
+) -> Result<(), SmokeFailure> {
+    let mut command = Command::new(binary_path);
+    command
+        .args(args)
+        .env_clear()
+        .env("LD_LIBRARY_PATH", rustc_lib_path)
+        .env("LANG", "C")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    for variable in &["PATH", "HOME"] {
+        if let Some(value) = env::var_os(variable) {
+            command.env(variable, value);
+        }
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|error| SmokeFailure::NotStarted(error.to_string()))?;
+
+    // read stderr on the side, a binary with a lot to say would block on a full pipe otherwise
+    let mut stderr_pipe = child.stderr.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        while let Ok(count) = stderr_pipe.read(&mut buffer) {
+            if count == 0 || sender.send(buffer[..count].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if started.elapsed() < SMOKE_TIMEOUT => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(SmokeFailure::Timeout);
+            }
+        }
+    };
+    // a process the binary left behind can keep stderr open, don't wait past the timeout for it
+    let mut stderr = Vec::new();
+    while let Some(remaining) = SMOKE_TIMEOUT.checked_sub(started.elapsed()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(chunk) => stderr.extend(chunk),
+            Err(_) => break,
+        }
+    }
+    let stderr = String::from_utf8_lossy(&stderr).into_owned();
+    match (status.code(), status.signal()) {
+        (Some(0), _) => Ok(()),
+        (Some(code), _) => Err(SmokeFailure::Status(code, stderr)),
+        (None, Some(signal)) => Err(SmokeFailure::Signal(signal, stderr)),
+        (None, None) => Err(SmokeFailure::Status(-1, stderr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use std::fs;
+
+    #[test]
+    fn smoke_test_finds_runtime_failures() {
+        let cargo_home = FakeCargoHome::new("smoke")
+            .registry_crate("panicky", "0.1.0", &["panicky"])
+            .panicking_binary("panicky")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        // links are all the check looks at without --smoke
+        let output = cargo_home.run(&["check", "--full"]);
+        assert!(output.status.success());
+
+        let output = cargo_home.run(&["check", "--full", "--smoke"]);
+        assert_eq!(output.status.code(), Some(5));
+        assert!(output
+            .stdout
+            .ends_with("\n  Crates needing rebuild: panicky\n"));
+        assert_eq!(
+            output.stderr,
+            "    Binary 'panicky' fails at runtime: 'panicky --version' exits with status 101
+\t\tthread 'main' panicked at 'couldn't find the sysroot'
+    To fix: rebuild the crate, its binaries link fine but fail when they are run\n"
+        );
+
+        // the cargo config can skip crates that have no harmless way to be run
+        fs::write(
+            cargo_home.path().join("config"),
+            "[rebuild-check.smoke]\npanicky = \"\"\n",
+        )
+        .unwrap();
+        let output = cargo_home.run(&["check", "--full", "--smoke"]);
+        assert!(output.status.success());
+    }
+
+    #[test]
+    fn smoke_test_does_not_wait_for_leftover_processes() {
+        let cargo_home = FakeCargoHome::new("smoke_leftover")
+            .script(
+                "lingering",
+                "#!/bin/sh\necho 'no config file' >&2\nsleep 30 &\nexit 1\n",
+            )
+            .build();
+
+        let started = Instant::now();
+        match smoke_test(&cargo_home.bin_dir().join("lingering"), &[], "") {
+            Err(SmokeFailure::Status(1, stderr)) => assert_eq!(stderr, "no config file\n"),
+            _ => panic!("the script should fail with status 1"),
+        }
+        assert!(started.elapsed() < SMOKE_TIMEOUT + Duration::from_secs(5));
+    }
+}
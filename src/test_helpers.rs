@@ -1,5 +1,23 @@
-use std::path::PathBuf;
-use std::process::Command;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{BufRead, BufReader};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// the rustc of the current toolchain itself, for tests that replace RUSTUP_HOME
+pub(crate) fn toolchain_rustc() -> String {
+    let output = Command::new("rustc")
+        .arg("--print")
+        .arg("sysroot")
+        .output()
+        .unwrap();
+    let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    format!("{}/bin/rustc", sysroot)
+}
 
 pub(crate) fn run_cargo_build() -> PathBuf {
     // run a "cargo build, then we can run the resulting binary and test things
@@ -14,3 +32,607 @@ pub(crate) fn run_cargo_build() -> PathBuf {
     // return the directory since we are going to reuse it
     dir
 }
+
+// C sources most fixtures are built from
+const MAIN: &str = "int main(void) { return 0; }\n";
+const CALLS_FOO: &str = "int foo(void);\nint main(void) { return foo(); }\n";
+const DEFINES_FOO: &str = "int foo(void) { return 0; }\n";
+const DEFINES_BAR: &str = "int bar(void) { return 0; }\n";
+const FOO_CALLS_BAR: &str = "int bar(void);\nint foo(void) { return bar(); }\n";
+
+// what a run of cargo-rebuild-check printed and how it exited
+pub(crate) struct RunOutput {
+    pub(crate) status: ExitStatus,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+}
+
+// used to give every fixture its own directory, tests run in parallel
+static FIXTURE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// a fresh directory below the system temp dir, removed again when dropped
+pub(crate) struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "cargo-rebuild-check-{}-{}-{}",
+            name,
+            std::process::id(),
+            FIXTURE_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// a synthetic $CARGO_HOME containing a .crates.toml, a bin/ directory and
+// small ELF binaries, some of which link against libraries that no longer exist
+pub(crate) struct FakeCargoHome {
+    dir: TempDir,
+    crates_toml: Vec<String>,
+    // run with $CARGO_HOME/rustup as RUSTUP_HOME
+    rustup_env: bool,
+    // the rustc of default_toolchain(), the one of the current toolchain otherwise
+    default_rustc: Option<PathBuf>,
+}
+
+impl FakeCargoHome {
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = TempDir::new(name);
+        fs::create_dir_all(dir.path.join("bin")).unwrap();
+        fs::create_dir_all(dir.path.join("build")).unwrap();
+        Self {
+            dir,
+            crates_toml: vec![String::from("[v1]")],
+            rustup_env: false,
+            default_rustc: None,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.dir.path
+    }
+
+    pub(crate) fn bin_dir(&self) -> PathBuf {
+        self.dir.path.join("bin")
+    }
+
+    // run cargo-rebuild-check with the toolchains in $CARGO_HOME/rustup instead of the real ones
+    pub(crate) fn with_rustup_env(mut self) -> Self {
+        self.rustup_env = true;
+        self
+    }
+
+    // add a line as it would be written into .crates.toml by "cargo install"
+    pub(crate) fn crates_toml_line(mut self, line: &str) -> Self {
+        self.crates_toml.push(line.to_string());
+        self
+    }
+
+    // add a crates.io crate to .crates.toml
+    pub(crate) fn registry_crate(self, name: &str, version: &str, binaries: &[&str]) -> Self {
+        let bins = binaries
+            .iter()
+            .map(|bin| format!("\"{}\"", bin))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let line = format!(
+            "\"{} {} (registry+https://github.com/rust-lang/crates.io-index)\" = [{}]",
+            name, version, bins
+        );
+        self.crates_toml_line(&line)
+    }
+
+    // a shell script that gets run instead of the program "name", fake-bin/ comes first in PATH
+    pub(crate) fn fake_program(self, name: &str, script: &str) -> Self {
+        let fake_bin = self.dir.path.join("fake-bin");
+        fs::create_dir_all(&fake_bin).unwrap();
+        let path = fake_bin.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{}", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        self
+    }
+
+    // a rustup with "default" as the default toolchain and the given (dir, toolchain) overrides
+    pub(crate) fn fake_rustup(self, default: &str, overrides: &[(&str, &str)]) -> Self {
+        let override_list = if overrides.is_empty() {
+            String::from("no overrides\n")
+        } else {
+            overrides
+                .iter()
+                .map(|(dir, toolchain)| format!("{}\t{}\n", dir, toolchain))
+                .collect()
+        };
+        self.fake_program(
+            "rustup",
+            &format!(
+                "case \"$1 $2\" in\n\
+                 '--version ') echo 'rustup 1.13.0 (ea9259c1b 2018-07-16)' ;;\n\
+                 'default '|'show active-toolchain') echo '{0} (default)' ;;\n\
+                 'override list') printf '%s' '{1}' ;;\n\
+                 *) exit 1 ;;\n\
+                 esac\n",
+                default, override_list
+            ),
+        )
+    }
+
+    // a crates.io crate with a single binary of the same name that only needs libc
+    pub(crate) fn working_crate(self, name: &str, version: &str) -> Self {
+        self.registry_crate(name, version, &[name])
+            .working_binary(name)
+    }
+
+    // the same, but its binary links against "library", which does not exist
+    pub(crate) fn broken_crate(self, name: &str, version: &str, library: &str) -> Self {
+        self.registry_crate(name, version, &[name])
+            .broken_binary(name, library)
+    }
+
+    // compile a binary into bin/ that only needs libc
+    pub(crate) fn working_binary(self, name: &str) -> Self {
+        self.compile(MAIN, &self.bin_dir().join(name), &[]);
+        self
+    }
+
+    // a binary that prints the name it was called by, argv[0]
+    pub(crate) fn name_printing_binary(self, name: &str) -> Self {
+        self.compile(
+            "#include <stdio.h>\nint main(int argc, char **argv) { puts(argv[0]); return 0; }\n",
+            &self.bin_dir().join(name),
+            &[],
+        );
+        self
+    }
+
+    // a binary that links fine, but panics like a rustc-driver tool run against the wrong sysroot
+    pub(crate) fn panicking_binary(self, name: &str) -> Self {
+        self.compile(
+            "#include <stdio.h>\n\
+             int main(void) {\n\
+             fputs(\"thread 'main' panicked at 'couldn't find the sysroot'\\n\", stderr);\n\
+             return 101;\n\
+             }\n",
+            &self.bin_dir().join(name),
+            &[],
+        );
+        self
+    }
+
+    // compile a statically linked binary into bin/
+    pub(crate) fn static_binary(self, name: &str) -> Self {
+        self.compile(MAIN, &self.bin_dir().join(name), &[String::from("-static")]);
+        self
+    }
+
+    // an executable script with the given content in bin/
+    pub(crate) fn script(self, name: &str, content: &str) -> Self {
+        let path = self.bin_dir().join(name);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        self
+    }
+
+    // a binary whose ELF header claims it was built for a different machine
+    pub(crate) fn foreign_binary(self, name: &str) -> Self {
+        let cargo_home = self.working_binary(name);
+        cargo_home.claim_foreign_machine(name);
+        cargo_home
+    }
+
+    // the same, statically linked, so there are no links that could tell it is broken
+    pub(crate) fn foreign_static_binary(self, name: &str) -> Self {
+        let cargo_home = self.static_binary(name);
+        cargo_home.claim_foreign_machine(name);
+        cargo_home
+    }
+
+    fn claim_foreign_machine(&self, name: &str) {
+        let path = self.bin_dir().join(name);
+        let mut content = fs::read(&path).unwrap();
+        // e_machine, AArch64 or x86-64 if we are running on AArch64
+        let machine: u16 = if cfg!(target_arch = "aarch64") {
+            62
+        } else {
+            183
+        };
+        let (low, high) = (machine as u8, (machine >> 8) as u8);
+        if content[5] == 1 {
+            content[0x12..0x14].copy_from_slice(&[low, high]);
+        } else {
+            content[0x12..0x14].copy_from_slice(&[high, low]);
+        }
+        fs::write(&path, content).unwrap();
+    }
+
+    // a binary asking for a dynamic loader that does not exist, like a musl binary on glibc
+    pub(crate) fn missing_interpreter_binary(self, name: &str, interpreter: &str) -> Self {
+        self.compile(
+            MAIN,
+            &self.bin_dir().join(name),
+            &[format!("-Wl,--dynamic-linker={}", interpreter)],
+        );
+        self
+    }
+
+    // compile a binary into bin/ that links against "library" (for example
+    // "libfoo-0123456789abcdef.so") and delete the library afterwards
+    pub(crate) fn broken_binary(self, name: &str, library: &str) -> Self {
+        let lib_path = self.build_dir().join(library);
+        self.compile_library(DEFINES_FOO, &lib_path, &[]);
+        self.compile(
+            CALLS_FOO,
+            &self.bin_dir().join(name),
+            &self.link_args(&self.build_dir(), library, None),
+        );
+
+        // now break the link
+        fs::remove_file(&lib_path).unwrap();
+        self
+    }
+
+    // compile "library" into the lib dir of a toolchain in $CARGO_HOME/rustup
+    pub(crate) fn toolchain_library(self, toolchain: &str, library: &str) -> Self {
+        let lib_dir = self.toolchain_lib_dir(toolchain);
+        fs::create_dir_all(&lib_dir).unwrap();
+        self.compile_library(DEFINES_FOO, &lib_dir.join(library), &[]);
+        self
+    }
+
+    // a toolchain whose rustc reports its own dir as the sysroot; with_rustup_env() makes it the
+    // default
+    pub(crate) fn default_toolchain(mut self, toolchain: &str) -> Self {
+        let toolchain_dir = self.dir.path.join("rustup/toolchains").join(toolchain);
+        fs::create_dir_all(toolchain_dir.join("lib")).unwrap();
+        fs::create_dir_all(toolchain_dir.join("bin")).unwrap();
+        let rustc = toolchain_dir.join("bin/rustc");
+        fs::write(
+            &rustc,
+            format!(
+                "#!/bin/sh\nif [ \"$1\" = --print ]; then echo '{}'; else exec '{}' \"$@\"; fi\n",
+                toolchain_dir.display(),
+                toolchain_rustc()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&rustc, fs::Permissions::from_mode(0o755)).unwrap();
+        self.default_rustc = Some(rustc);
+        self
+    }
+
+    // compile a binary into bin/ that finds "library" in the lib dir of a toolchain via its rpath
+    pub(crate) fn toolchain_binary(self, name: &str, toolchain: &str, library: &str) -> Self {
+        let cargo_home = self.toolchain_library(toolchain, library);
+        let lib_dir = cargo_home.toolchain_lib_dir(toolchain);
+        cargo_home.compile(
+            CALLS_FOO,
+            &cargo_home.bin_dir().join(name),
+            &cargo_home.link_args(&lib_dir, library, Some(&lib_dir.display().to_string())),
+        );
+        cargo_home
+    }
+
+    // like toolchain_binary(), but the rpath also has build/, where a copy of "library" is
+    pub(crate) fn fallback_toolchain_binary(
+        self,
+        name: &str,
+        toolchain: &str,
+        library: &str,
+    ) -> Self {
+        let cargo_home = self.toolchain_library(toolchain, library);
+        let lib_dir = cargo_home.toolchain_lib_dir(toolchain);
+        let build_dir = cargo_home.build_dir();
+        fs::copy(lib_dir.join(library), build_dir.join(library)).unwrap();
+        let rpath = format!("{}:{}", lib_dir.display(), build_dir.display());
+        cargo_home.compile(
+            CALLS_FOO,
+            &cargo_home.bin_dir().join(name),
+            &cargo_home.link_args(&lib_dir, library, Some(&rpath)),
+        );
+        cargo_home
+    }
+
+    // compile a binary into bin/ that finds "library" via its rpath, and "library" links against
+    // "missing", which is deleted afterwards
+    pub(crate) fn chained_binary(self, name: &str, library: &str, missing: &str) -> Self {
+        let build_dir = self.build_dir();
+        self.compile_library(DEFINES_BAR, &build_dir.join(missing), &[]);
+        self.compile_library(
+            FOO_CALLS_BAR,
+            &build_dir.join(library),
+            &self.link_args(&build_dir, missing, None),
+        );
+        self.compile(
+            CALLS_FOO,
+            &self.bin_dir().join(name),
+            &self.link_args(&build_dir, library, Some(&build_dir.display().to_string())),
+        );
+
+        fs::remove_file(build_dir.join(missing)).unwrap();
+        self
+    }
+
+    // compile a binary into bin/ with an old style DT_RPATH to build/, where "library" and
+    // "dependency" are; "library" itself has no rpath and finds "dependency" via the binary's
+    pub(crate) fn rpath_chained_binary(self, name: &str, library: &str, dependency: &str) -> Self {
+        let build_dir = self.build_dir();
+        self.compile_library(DEFINES_BAR, &build_dir.join(dependency), &[]);
+        self.compile_library(
+            FOO_CALLS_BAR,
+            &build_dir.join(library),
+            &self.link_args(&build_dir, dependency, None),
+        );
+        let mut args = self.link_args(&build_dir, library, Some(&build_dir.display().to_string()));
+        args.push(String::from("-Wl,--disable-new-dtags"));
+        self.compile(CALLS_FOO, &self.bin_dir().join(name), &args);
+        self
+    }
+
+    // compile a binary into bin/ that needs version VERS_2 of "library", then replace the
+    // library with one that only defines VERS_1; the binary finds the library via its rpath
+    pub(crate) fn version_mismatch_binary(self, name: &str, library: &str) -> Self {
+        let build_dir = self.build_dir();
+        let build_library = |version: &str| {
+            let script = self.write_version_script(
+                &format!("{}-{}", library, version),
+                &format!("{} {{ global: foo; local: *; }};\n", version),
+            );
+            self.compile_library(
+                DEFINES_FOO,
+                &build_dir.join(library),
+                &[format!("-Wl,--version-script,{}", script.display())],
+            );
+        };
+        build_library("VERS_2");
+        self.compile(
+            CALLS_FOO,
+            &self.bin_dir().join(name),
+            &self.link_args(&build_dir, library, Some(&build_dir.display().to_string())),
+        );
+
+        // an older version of the library gets installed
+        build_library("VERS_1");
+        self
+    }
+
+    // compile a binary into bin/ that needs foo() from version VERS_1 of "library" and, weakly,
+    // bar() from VERS_2, then replace the library with one that only defines VERS_1; linkers
+    // don't mark versions weak on their own, so the flag gets patched in
+    pub(crate) fn weak_version_binary(self, name: &str, library: &str) -> Self {
+        let build_dir = self.build_dir();
+        let build_library = |source: &str, versions: &str| {
+            let script = self.write_version_script(&format!("{}-versions", library), versions);
+            self.compile_library(
+                source,
+                &build_dir.join(library),
+                &[format!("-Wl,--version-script,{}", script.display())],
+            );
+        };
+        build_library(
+            "int foo(void) { return 0; }\nint bar(void) { return 0; }\n",
+            "VERS_1 { global: foo; local: *; };\nVERS_2 { global: bar; } VERS_1;\n",
+        );
+        let output = self.bin_dir().join(name);
+        self.compile(
+            "int foo(void);\nint bar(void) __attribute__((weak));\n\
+             int main(void) { return foo() + (bar ? bar() : 0); }\n",
+            &output,
+            &self.link_args(&build_dir, library, Some(&build_dir.display().to_string())),
+        );
+
+        // Elf_Vernaux starts with the ELF hash of the version name, followed by the flags
+        let hash = b"VERS_2".iter().fold(0u32, |hash, byte| {
+            let hash = (hash << 4) + u32::from(*byte);
+            let high = hash & 0xf000_0000;
+            (hash ^ (high >> 24)) & !high
+        });
+        let mut content = fs::read(&output).unwrap();
+        let mut vernaux = hash.to_ne_bytes().to_vec();
+        vernaux.extend_from_slice(&[0, 0]);
+        let position = content
+            .windows(vernaux.len())
+            .position(|window| window == &vernaux[..])
+            .unwrap();
+        content[position + 4..position + 6].copy_from_slice(&2u16.to_ne_bytes());
+        fs::write(&output, content).unwrap();
+
+        build_library(DEFINES_FOO, "VERS_1 { global: foo; local: *; };\n");
+        self
+    }
+
+    // compile a binary into bin/ that calls foo() and bar() from "library", then replace the
+    // library with one under the same soname that lost bar(); ldd finds nothing wrong
+    pub(crate) fn abi_changed_binary(self, name: &str, library: &str) -> Self {
+        let build_dir = self.build_dir();
+        let lib_path = build_dir.join(library);
+        self.compile_library(
+            "int foo(void) { return 0; }\nint bar(void) { return 0; }\n",
+            &lib_path,
+            &[],
+        );
+        self.compile(
+            "int foo(void);\nint bar(void);\nint main(void) { return foo() + bar(); }\n",
+            &self.bin_dir().join(name),
+            &self.link_args(&build_dir, library, Some(&build_dir.display().to_string())),
+        );
+
+        self.compile_library(DEFINES_FOO, &lib_path, &[]);
+        self
+    }
+
+    // write .crates.toml, the fixture is ready to use afterwards
+    pub(crate) fn build(self) -> Self {
+        let mut file = File::create(self.dir.path.join(".crates.toml")).unwrap();
+        for line in &self.crates_toml {
+            writeln!(file, "{}", line).unwrap();
+        }
+        self
+    }
+
+    // run the freshly built cargo-rebuild-check against this $CARGO_HOME
+    pub(crate) fn run(&self, args: &[&str]) -> RunOutput {
+        self.run_with_env(args, &[])
+    }
+
+    pub(crate) fn run_with_env(&self, args: &[&str], envs: &[(&str, &str)]) -> RunOutput {
+        let output = self.command(args, envs).output().unwrap();
+        RunOutput {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    }
+
+    // start cargo-rebuild-check in the background, for commands that keep running
+    pub(crate) fn spawn(&self, args: &[&str]) -> Child {
+        self.command(args, &[])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap()
+    }
+
+    // the same, with the lines it prints to stdout, read in the background so tests can time out
+    // instead of hanging forever
+    pub(crate) fn spawn_lines(&self, args: &[&str]) -> (Child, Receiver<String>) {
+        let mut child = self.spawn(args);
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in stdout.lines() {
+                if sender.send(line.unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        (child, receiver)
+    }
+
+    fn command(&self, args: &[&str], envs: &[(&str, &str)]) -> Command {
+        let mut dir = run_cargo_build();
+        dir.push("target");
+        dir.push("debug");
+        dir.push("cargo-rebuild-check");
+        let mut cmd = Command::new(&dir);
+        // a rust-toolchain file above the working directory would pin a toolchain
+        cmd.args(args)
+            .current_dir(&self.dir.path)
+            .env("CARGO_HOME", &self.dir.path)
+            // don't pick up the install root of the developer running the tests
+            .env_remove("CARGO_INSTALL_ROOT")
+            .env("LANG", "en_US")
+            .env("LC_ALL", "en_US");
+        // the cargo and rustc of the current toolchain follow the fake programs, the rustup
+        // proxies would ask the fake rustup
+        let fake_bin = self.dir.path.join("fake-bin");
+        if fake_bin.is_dir() {
+            let toolchain_bin = PathBuf::from(toolchain_rustc());
+            cmd.env(
+                "PATH",
+                format!(
+                    "{}:{}:{}",
+                    fake_bin.display(),
+                    toolchain_bin.parent().unwrap().display(),
+                    std::env::var("PATH").unwrap()
+                ),
+            );
+        }
+        if self.rustup_env {
+            let rustc = match self.default_rustc {
+                Some(ref rustc) => rustc.display().to_string(),
+                None => toolchain_rustc(),
+            };
+            cmd.env("RUSTUP_HOME", self.dir.path.join("rustup"))
+                .env("RUSTC", rustc);
+        }
+        for (key, value) in envs {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+
+    fn build_dir(&self) -> PathBuf {
+        self.dir.path.join("build")
+    }
+
+    pub(crate) fn toolchain_lib_dir(&self, toolchain: &str) -> PathBuf {
+        self.dir
+            .path
+            .join("rustup/toolchains")
+            .join(toolchain)
+            .join("lib")
+    }
+
+    // the arguments to link against "library" in "dir", and to find it there via "rpath"
+    fn link_args(&self, dir: &Path, library: &str, rpath: Option<&str>) -> Vec<String> {
+        let mut args = vec![
+            String::from("-L"),
+            dir.display().to_string(),
+            format!("-l:{}", library),
+        ];
+        if let Some(rpath) = rpath {
+            args.push(format!("-Wl,-rpath,{}", rpath));
+        }
+        args
+    }
+
+    // compile "source" into "output", "extra_args" come after the source file for the linker
+    fn compile(&self, source: &str, output: &Path, extra_args: &[String]) {
+        let name = output.file_name().unwrap().to_str().unwrap();
+        let source = self.write_source(name, source);
+        let cc = Command::new("cc")
+            .arg(&source)
+            .arg("-o")
+            .arg(output)
+            .args(extra_args)
+            .status()
+            .unwrap();
+        assert!(cc.success(), "failed to compile {}", name);
+    }
+
+    // the same for a shared library, its file name is its soname
+    fn compile_library(&self, source: &str, output: &Path, extra_args: &[String]) {
+        let name = output.file_name().unwrap().to_str().unwrap();
+        let mut args = vec![
+            String::from("-shared"),
+            String::from("-fPIC"),
+            format!("-Wl,-soname,{}", name),
+        ];
+        args.extend_from_slice(extra_args);
+        self.compile(source, output, &args);
+    }
+
+    fn write_source(&self, name: &str, content: &str) -> PathBuf {
+        self.write_build_file(&format!("{}.c", name), content)
+    }
+
+    // a linker version script, for -Wl,--version-script
+    fn write_version_script(&self, name: &str, content: &str) -> PathBuf {
+        self.write_build_file(&format!("{}.map", name), content)
+    }
+
+    fn write_build_file(&self, file_name: &str, content: &str) -> PathBuf {
+        let path = self.dir.path.join("build").join(file_name);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(content.as_bytes()).unwrap();
+        path
+    }
+}
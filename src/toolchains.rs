@@ -0,0 +1,217 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rayon::iter::*;
+
+use crate::core::*;
+use crate::libs::*;
+use crate::parse::*;
+use crate::script::*;
+use crate::wrapper::*;
+
+// the binaries of a crate and the rust libraries (toolchain or not) they load
+pub(crate) struct RustLibraryUsers {
+    name: String,
+    binaries: Vec<(String, Vec<String>)>,
+}
+
+fn rust_libraries(package: &CrateInfo, bin_dir: &Path, rustc_lib_path: &str) -> RustLibraryUsers {
+    let mut binaries = Vec::new();
+    for binary in &package.binaries {
+        let path = bin_dir.join(binary);
+        // a wrapped binary needs the toolchain its wrapper points it to
+        let (path, library_path) = match wrapped_binary(bin_dir, binary) {
+            Some(wrapper) => (wrapper.real.clone(), wrapper.library_path(rustc_lib_path)),
+            None if !path.is_file() || read_shebang(&path).is_some() => continue,
+            None => (path, rustc_lib_path.to_string()),
+        };
+        // found or not, the hash in the name tells which toolchain it belongs to
+        let libraries = resolve_libraries(&path, &library_path)
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| classify_library(name) != LibraryKind::System)
+            .collect::<Vec<_>>();
+        if !libraries.is_empty() {
+            binaries.push((binary.clone(), libraries));
+        }
+    }
+    RustLibraryUsers {
+        name: package.name.clone(),
+        binaries,
+    }
+}
+
+pub(crate) fn rust_library_users(
+    packages: &[CrateInfo],
+    bin_dir: &Path,
+    rustc_lib_path: &str,
+) -> Vec<RustLibraryUsers> {
+    packages
+        .par_iter()
+        .map(|package| rust_libraries(package, bin_dir, rustc_lib_path))
+        .filter(|users| !users.binaries.is_empty())
+        .collect()
+}
+
+pub(crate) fn installed_toolchains(toolchains_dir: &Path) -> Vec<PathBuf> {
+    let mut toolchains = match fs::read_dir(toolchains_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir())
+            .collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    toolchains.sort();
+    toolchains
+}
+
+// "nightly-2018-07-01", or toml with a 'channel = "nightly-2018-07-01"' line
+fn read_toolchain_file(dir: &Path) -> Option<String> {
+    let content = fs::read_to_string(dir.join("rust-toolchain"))
+        .or_else(|_| fs::read_to_string(dir.join("rust-toolchain.toml")))
+        .ok()?;
+    let lines = content.lines().map(str::trim).collect::<Vec<_>>();
+    let line = lines
+        .iter()
+        .find(|line| line.starts_with("channel"))
+        .or_else(|| lines.iter().find(|line| !line.is_empty()))?;
+    Some(
+        line.rsplit('=')
+            .next()?
+            .trim()
+            .trim_matches('"')
+            .to_string(),
+    )
+}
+
+// toolchains that rustup overrides or the rust-toolchain file of the current project ask for,
+// removing them breaks these projects even if no installed binary needs them
+pub(crate) fn pinned_toolchains() -> Vec<PathBuf> {
+    let mut names = Vec::new();
+    // one "<dir>\t<toolchain>" line per override, or "no overrides"
+    if let Ok(output) = Command::new("rustup").args(&["override", "list"]).output() {
+        names.extend(
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter(|line| line.contains('\t'))
+                .filter_map(|line| line.rsplit('\t').next())
+                .map(|name| name.trim().to_string()),
+        );
+    }
+    if let Ok(current_dir) = env::current_dir() {
+        names.extend(current_dir.ancestors().find_map(read_toolchain_file));
+    }
+    names
+        .iter()
+        .filter_map(|name| find_toolchain(name))
+        .collect()
+}
+
+// list which crates need which toolchain, returns the toolchains nobody needs that are neither
+// the default one nor pinned
+pub(crate) fn print_toolchain_users(
+    toolchains: &[PathBuf],
+    users: &[RustLibraryUsers],
+    rustc_lib_path: &str,
+    pinned: &[PathBuf],
+) -> Vec<String> {
+    let mut unused = Vec::new();
+    for toolchain in toolchains {
+        let name = toolchain
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let lib_dir = toolchain.join("lib");
+        let is_default = Path::new(rustc_lib_path).starts_with(toolchain);
+        if is_default {
+            println!("  Toolchain {} (default)", name);
+        } else {
+            println!("  Toolchain {}", name);
+        }
+
+        let mut needed_by = Vec::new();
+        for crate_users in users {
+            let binaries = crate_users
+                .binaries
+                .iter()
+                .filter(|(_, libraries)| {
+                    libraries
+                        .iter()
+                        .any(|library| lib_dir.join(library).is_file())
+                })
+                .map(|(binary, _)| binary.as_str())
+                .collect::<Vec<_>>();
+            if !binaries.is_empty() {
+                needed_by.push(format!("{} ({})", crate_users.name, binaries.join(" ")));
+            }
+        }
+        if !needed_by.is_empty() {
+            println!("    Needed by: {}", needed_by.join(", "));
+        } else if is_default {
+            // it provides the rustc and cargo everything is checked and rebuilt with
+            println!("    Not needed by any installed binary, but it is the default toolchain");
+        } else if pinned.contains(toolchain) {
+            println!(
+                "    Not needed by any installed binary, but an override or rust-toolchain file \
+                 asks for it"
+            );
+        } else {
+            println!("    Not needed by any installed binary");
+            unused.push(name);
+        }
+    }
+    unused
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::*;
+
+    #[test]
+    fn toolchains_report() {
+        let cargo_home = FakeCargoHome::new("toolchains")
+            .registry_crate("pinned", "0.1.0", &["pinned", "helper"])
+            .toolchain_binary(
+                "pinned",
+                "old-x86_64-unknown-linux-gnu",
+                "libold-0123456789abcdef.so",
+            )
+            .working_binary("helper")
+            .toolchain_library(
+                "unused-x86_64-unknown-linux-gnu",
+                "libstd-0123456789abcdef.so",
+            )
+            .working_crate("fine", "1.0.0")
+            .default_toolchain("default-x86_64-unknown-linux-gnu")
+            .toolchain_library(
+                "workspace-x86_64-unknown-linux-gnu",
+                "libstd-0123456789abcdef.so",
+            )
+            .fake_rustup(
+                "default-x86_64-unknown-linux-gnu",
+                &[("/tmp/project", "workspace-x86_64-unknown-linux-gnu")],
+            )
+            .with_rustup_env()
+            .build();
+
+        let output = cargo_home.run(&["toolchains"]);
+        assert!(output.stderr.is_empty());
+        // what rustc, cargo and the overrides use stays, needed by a binary or not
+        assert_eq!(
+            output.stdout,
+            "  Toolchain default-x86_64-unknown-linux-gnu (default)
+    Not needed by any installed binary, but it is the default toolchain
+  Toolchain old-x86_64-unknown-linux-gnu
+    Needed by: pinned (pinned)
+  Toolchain unused-x86_64-unknown-linux-gnu
+    Not needed by any installed binary
+  Toolchain workspace-x86_64-unknown-linux-gnu
+    Not needed by any installed binary, but an override or rust-toolchain file asks for it
+
+  Safe to remove: unused-x86_64-unknown-linux-gnu\n"
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+
+use crate::cache::*;
+use crate::core::*;
+use crate::history::*;
+use crate::parse::*;
+
+// rustup and cargo touch many files in a row, wait until they are done before checking again
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// a change to one of the watched paths, the .crates.toml files are watched via their install root
+enum WatchedPath {
+    LibraryDir,
+    InstallRoot,
+}
+
+pub(crate) fn watch(
+    install_roots: &[PathBuf],
+    cargo_home: &Path,
+    use_cache: bool,
+    options: &CheckOptions,
+    do_auto_rebuild: bool,
+) {
+    // names of the broken crates per install root, as of the last check
+    let mut known_broken: HashMap<&Path, HashSet<String>> = HashMap::new();
+    // one inotify instance for the whole session, events between two waits are not lost
+    let mut inotify = match Inotify::init() {
+        Ok(inotify) => inotify,
+        Err(e) => {
+            eprintln!("Failed to initialize inotify: '{}'", e);
+            std::process::exit(3);
+        }
+    };
+    let mut watches = HashMap::new();
+
+    loop {
+        // the default toolchain may have changed as well
+        let rust_lib_path = get_rustc_lib_path();
+        // load the cache again, its fingerprint of the library search path is outdated now
+        let cache = if use_cache {
+            Some(Cache::load(cargo_home, &rust_lib_path))
+        } else {
+            None
+        };
+        let mut history = HistoryRecord::new(RunKind::Watch, get_rustc_version(), &rust_lib_path);
+
+        for install_root in install_roots {
+            let file = read_crates_toml(install_root);
+            let packages = match get_installed_crate_information(file) {
+                Ok(pkgs) => pkgs,
+                Err(error) => {
+                    eprintln!(
+                        "  Failed to read crates installed into '{}': {:?}",
+                        install_root.display(),
+                        error
+                    );
+                    continue;
+                }
+            };
+            let reports = check_crates(
+                &packages,
+                &rust_lib_path,
+                &install_root.join("bin"),
+                cache.as_ref(),
+                options,
+            );
+            let broken_pkgs = broken_crates(&reports);
+            history.add_crates(install_root, &reports, false);
+            history.add_root(install_root);
+
+            let previously_broken = known_broken
+                .remove(install_root.as_path())
+                .unwrap_or_default();
+            let newly_broken = broken_pkgs
+                .iter()
+                .filter(|pkg| !previously_broken.contains(&pkg.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let broken_names = broken_pkgs
+                .iter()
+                .map(|pkg| pkg.name.clone())
+                .collect::<HashSet<_>>();
+            let mut fixed = previously_broken
+                .difference(&broken_names)
+                .cloned()
+                .collect::<Vec<_>>();
+            fixed.sort();
+
+            if (!newly_broken.is_empty() || !fixed.is_empty()) && install_roots.len() > 1 {
+                println!("  Install root '{}'", install_root.display());
+            }
+            if !newly_broken.is_empty() {
+                let pkgs_string = newly_broken
+                    .iter()
+                    .map(|pkg| pkg.name.clone())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                println!("  Newly broken crates: {}", pkgs_string);
+            }
+            if !fixed.is_empty() {
+                println!("  Crates working again: {}", fixed.join(" "));
+            }
+
+            if do_auto_rebuild && !newly_broken.is_empty() {
+                let rebuilds = rebuild_crates(&newly_broken, install_root, options);
+                history.add_rebuilds(install_root, &rebuilds);
+                let list_of_failures = failed_rebuilds(&rebuilds);
+                if !list_of_failures.is_empty() {
+                    println!("    Failed rebuilds: {}", list_of_failures.join(" "));
+                }
+            }
+
+            known_broken.insert(install_root, broken_names);
+        }
+
+        if let Some(cache) = cache {
+            cache.save();
+        }
+        history.append_to(cargo_home);
+
+        wait_for_changes(&mut inotify, &mut watches, install_roots, &rust_lib_path);
+    }
+}
+
+// watch a directory and everything below it, symlinks are not followed
+fn add_recursive_watch(
+    inotify: &mut Inotify,
+    watches: &mut HashMap<WatchDescriptor, WatchedPath>,
+    dir: &Path,
+    mask: WatchMask,
+) {
+    // a path may not exist, for example if rustup is not used
+    if let Ok(wd) = inotify.add_watch(dir, mask) {
+        watches.insert(wd, WatchedPath::LibraryDir);
+    }
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(Result::ok) {
+            if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                add_recursive_watch(inotify, watches, &entry.path(), mask);
+            }
+        }
+    }
+}
+
+fn wait_for_changes(
+    inotify: &mut Inotify,
+    watches: &mut HashMap<WatchDescriptor, WatchedPath>,
+    install_roots: &[PathBuf],
+    rust_lib_path: &str,
+) {
+    let mask = WatchMask::CREATE
+        | WatchMask::DELETE
+        | WatchMask::MOVED_FROM
+        | WatchMask::MOVED_TO
+        | WatchMask::CLOSE_WRITE
+        | WatchMask::DELETE_SELF
+        | WatchMask::MOVE_SELF;
+
+    // adding a watch again is a no-op, but directories created since the last wait need one
+    add_recursive_watch(inotify, watches, Path::new(rust_lib_path), mask);
+    // toolchains getting installed, updated or removed
+    if let Some(toolchains_dir) = get_rustup_toolchains_dir() {
+        add_recursive_watch(inotify, watches, &toolchains_dir, mask);
+    }
+    // cargo replaces .crates.toml on install, so watch the directory it lives in
+    for install_root in install_roots {
+        if let Ok(wd) = inotify.add_watch(install_root, mask) {
+            watches.insert(wd, WatchedPath::InstallRoot);
+        }
+    }
+    if watches.is_empty() {
+        eprintln!("Nothing to watch, none of the watched paths exist.");
+        std::process::exit(3);
+    }
+
+    println!("  Waiting for toolchain or crate changes...");
+    let mut buffer = [0; 4096];
+    'wait: loop {
+        let events = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events,
+            Err(e) => {
+                eprintln!("Failed to read inotify events: '{}'", e);
+                std::process::exit(3);
+            }
+        };
+        for event in events {
+            let relevant = match watches.get(&event.wd) {
+                Some(WatchedPath::LibraryDir) => true,
+                Some(WatchedPath::InstallRoot) => event.name == Some(OsStr::new(".crates.toml")),
+                None => false,
+            };
+            if relevant {
+                break 'wait;
+            }
+        }
+    }
+
+    // drop the events caused by the rest of the update
+    thread::sleep(SETTLE_TIME);
+    while let Ok(events) = inotify.read_events(&mut buffer) {
+        if events.count() == 0 {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::*;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn watch_reports_newly_broken_crate() {
+        let cargo_home = FakeCargoHome::new("watch")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        let (mut child, receiver) = cargo_home.spawn_lines(&["watch"]);
+        let next_line = || receiver.recv_timeout(Duration::from_secs(60)).unwrap();
+
+        assert_eq!(next_line(), "  Waiting for toolchain or crate changes...");
+
+        // installing a crate rewrites .crates.toml
+        let _cargo_home = cargo_home
+            .broken_crate("broken", "0.2.0", "libfoo-0123456789abcdef.so")
+            .build();
+
+        assert_eq!(next_line(), "  Newly broken crates: broken");
+        assert_eq!(next_line(), "  Waiting for toolchain or crate changes...");
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn watch_sees_toolchain_lib_changes() {
+        let cargo_home = FakeCargoHome::new("watch_toolchain")
+            .default_toolchain("default-x86_64-unknown-linux-gnu")
+            .toolchain_binary(
+                "pinned",
+                "default-x86_64-unknown-linux-gnu",
+                "libstd-0123456789abcdef.so",
+            )
+            .registry_crate("pinned", "0.1.0", &["pinned"])
+            .with_rustup_env()
+            .build();
+        // a cached result from before the update must not hide the breakage
+        assert!(cargo_home.run(&["check"]).status.success());
+
+        let (mut child, receiver) = cargo_home.spawn_lines(&["watch"]);
+        let next_line = || receiver.recv_timeout(Duration::from_secs(60)).unwrap();
+
+        assert_eq!(next_line(), "  Waiting for toolchain or crate changes...");
+
+        // an update of the toolchain replaces the libraries in its lib dir
+        let lib_dir = cargo_home.toolchain_lib_dir("default-x86_64-unknown-linux-gnu");
+        fs::remove_file(lib_dir.join("libstd-0123456789abcdef.so")).unwrap();
+        let _cargo_home = cargo_home.toolchain_library(
+            "default-x86_64-unknown-linux-gnu",
+            "libstd-fedcba9876543210.so",
+        );
+
+        assert_eq!(next_line(), "  Newly broken crates: pinned");
+        assert_eq!(next_line(), "  Waiting for toolchain or crate changes...");
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
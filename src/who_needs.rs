@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rayon::iter::*;
+
+use crate::core::*;
+use crate::loader::*;
+use crate::parse::*;
+use crate::script::*;
+use crate::wrapper::*;
+
+// what "who-needs" looks for: a soname like "libssl.so.1.1" (or "libssl.so" for every version of
+// it), or a path to a library or a directory of libraries, like a rustup toolchain
+pub(crate) enum LibraryQuery {
+    Soname(String),
+    Path(PathBuf),
+}
+
+impl LibraryQuery {
+    pub(crate) fn new(query: &str) -> Self {
+        if query.contains('/') {
+            let path = PathBuf::from(query);
+            // the library may be reached through a symlink, or may be gone already
+            LibraryQuery::Path(fs::canonicalize(&path).unwrap_or(path))
+        } else {
+            LibraryQuery::Soname(query.to_string())
+        }
+    }
+
+    fn matches(&self, node: &LibraryNode) -> bool {
+        match self {
+            LibraryQuery::Soname(soname) => {
+                node.name == *soname || node.name.starts_with(&format!("{}.", soname))
+            }
+            LibraryQuery::Path(query) => match node.path {
+                Some(ref path) => {
+                    let path = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+                    path.starts_with(query)
+                }
+                // a library that was deleted is only known by the name it is needed as
+                None => Path::new(&node.name).file_name() == query.file_name(),
+            },
+        }
+    }
+}
+
+// a binary that loads a wanted library, and the libraries in between
+struct Dependent<'a> {
+    binary: &'a str,
+    chain: Vec<String>,
+}
+
+fn dependents<'a>(
+    package: &'a CrateInfo,
+    bin_dir: &Path,
+    rustc_lib_path: &str,
+    query: &LibraryQuery,
+) -> Vec<Dependent<'a>> {
+    let mut dependents = Vec::new();
+    for binary in &package.binaries {
+        let path = bin_dir.join(binary);
+        let (path, library_path) = match wrapped_binary(bin_dir, binary) {
+            Some(wrapper) => (wrapper.real.clone(), wrapper.library_path(rustc_lib_path)),
+            None if !path.is_file() || read_shebang(&path).is_some() => continue,
+            None => (path, rustc_lib_path.to_string()),
+        };
+        // the same resolution the check uses
+        let libraries = resolve_libraries(&path, &library_path);
+        let tree = match dependency_tree(&path, &libraries, &library_path, None) {
+            Ok(tree) => tree,
+            Err(_) => continue,
+        };
+        // the shortest way to the library tells best why it is needed
+        let chain = chains_to(&tree, &|node: &LibraryNode| query.matches(node))
+            .into_iter()
+            .min_by_key(Vec::len);
+        if let Some(chain) = chain {
+            dependents.push(Dependent {
+                binary,
+                chain: chain.into_iter().map(String::from).collect(),
+            });
+        }
+    }
+    dependents
+}
+
+// list the crates and binaries below install_root that load the library, returns their names
+pub(crate) fn print_dependents(
+    packages: &[CrateInfo],
+    install_root: &Path,
+    rustc_lib_path: &str,
+    query: &LibraryQuery,
+) -> Vec<String> {
+    let bin_dir = install_root.join("bin");
+    let mut found = packages
+        .par_iter()
+        .map(|package| {
+            (
+                package,
+                dependents(package, &bin_dir, rustc_lib_path, query),
+            )
+        })
+        .filter(|(_, dependents)| !dependents.is_empty())
+        .collect::<Vec<_>>();
+    found.sort_by(|(a, _), (b, _)| a.name.cmp(&b.name).then(a.version.cmp(&b.version)));
+
+    for (package, dependents) in &found {
+        println!("  {} {}", package.name, package.version);
+        for dependent in dependents {
+            let (library, via) = dependent.chain.split_last().unwrap();
+            if via.is_empty() {
+                println!("    Binary '{}' needs {}", dependent.binary, library);
+            } else {
+                println!(
+                    "    Binary '{}' needs {} via {}",
+                    dependent.binary,
+                    library,
+                    via.join(" -> ")
+                );
+            }
+        }
+    }
+    found
+        .iter()
+        .map(|(package, _)| package.name.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_helpers::*;
+
+    #[test]
+    fn who_needs_library() {
+        let cargo_home = FakeCargoHome::new("who_needs")
+            .registry_crate("chain", "0.1.0", &["chain"])
+            .chained_binary("chain", "libouter.so", "libinner.so")
+            .working_crate("fine", "1.0.0")
+            .build();
+
+        let output = cargo_home.run(&["who-needs", "libinner.so"]);
+        assert!(output.stderr.is_empty());
+        assert_eq!(
+            output.stdout,
+            "  chain 0.1.0
+    Binary 'chain' needs libinner.so via libouter.so\n
+  Crates needing 'libinner.so': chain\n"
+        );
+
+        // every library in a directory
+        let build_dir = cargo_home.path().join("build");
+        let output = cargo_home.run(&["who-needs", build_dir.to_str().unwrap()]);
+        assert_eq!(
+            output.stdout,
+            format!(
+                "  chain 0.1.0
+    Binary 'chain' needs libouter.so\n
+  Crates needing '{}': chain\n",
+                build_dir.display()
+            )
+        );
+
+        // "libc.so" finds libc.so.6, which every binary needs
+        let output = cargo_home.run(&["who-needs", "libc.so"]);
+        let stdout = &output.stdout;
+        assert!(stdout.ends_with("\n  Crates needing 'libc.so': chain fine\n"));
+
+        // what removing a library broke
+        let output =
+            cargo_home.run(&["who-needs", build_dir.join("libinner.so").to_str().unwrap()]);
+        assert_eq!(
+            output.stdout,
+            format!(
+                "  chain 0.1.0
+    Binary 'chain' needs libinner.so via libouter.so\n
+  Crates needing '{}': chain\n",
+                build_dir.join("libinner.so").display()
+            )
+        );
+
+        let output = cargo_home.run(&["who-needs", "libnothing.so.1"]);
+        assert_eq!(
+            output.stdout,
+            "  No installed crate needs 'libnothing.so.1'\n"
+        );
+    }
+}
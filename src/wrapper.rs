@@ -0,0 +1,278 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::core::*;
+use crate::parse::*;
+use crate::relink::*;
+use crate::script::*;
+
+// inside the bin dir: the real binaries and the list of wrappers
+const WRAPPER_DIR: &str = ".rebuild-check";
+
+// a binary moved aside and replaced by a script that runs it with the lib dirs of a toolchain
+// in LD_LIBRARY_PATH
+pub(crate) struct Wrapper {
+    pub(crate) real: PathBuf,
+    pub(crate) lib_dirs: Vec<PathBuf>,
+}
+
+impl Wrapper {
+    // LD_LIBRARY_PATH as the real binary sees it, if it was ld_library_path before
+    pub(crate) fn library_path(&self, ld_library_path: &str) -> String {
+        let mut dirs = self.lib_dirs.clone();
+        dirs.extend(env::split_paths(ld_library_path).filter(|dir| !dir.as_os_str().is_empty()));
+        join_dirs(&dirs)
+    }
+}
+
+fn join_dirs(dirs: &[PathBuf]) -> String {
+    dirs.iter()
+        .map(|dir| dir.display().to_string())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn real_binary(bin_dir: &Path, binary: &str) -> PathBuf {
+    bin_dir.join(WRAPPER_DIR).join("real").join(binary)
+}
+
+// one line per wrapper: the binary and the lib dirs, separated by a tab
+fn read_wrappers(bin_dir: &Path) -> Vec<(String, Vec<PathBuf>)> {
+    let content =
+        fs::read_to_string(bin_dir.join(WRAPPER_DIR).join("wrappers")).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '\t');
+            let binary = parts.next()?;
+            let lib_dirs = env::split_paths(parts.next()?).collect();
+            Some((binary.to_string(), lib_dirs))
+        })
+        .collect()
+}
+
+fn write_wrappers(bin_dir: &Path, wrappers: &[(String, Vec<PathBuf>)]) -> io::Result<()> {
+    let content = wrappers
+        .iter()
+        .map(|(binary, lib_dirs)| format!("{}\t{}\n", binary, join_dirs(lib_dirs)))
+        .collect::<String>();
+    fs::create_dir_all(bin_dir.join(WRAPPER_DIR))?;
+    fs::write(bin_dir.join(WRAPPER_DIR).join("wrappers"), content)
+}
+
+// the wrapper that was installed for the binary, None once a rebuild replaced it
+pub(crate) fn wrapped_binary(bin_dir: &Path, binary: &str) -> Option<Wrapper> {
+    let (_, lib_dirs) = read_wrappers(bin_dir)
+        .into_iter()
+        .find(|(wrapped, _)| wrapped == binary)?;
+    let real = real_binary(bin_dir, binary);
+    if !real.is_file() || read_shebang(&bin_dir.join(binary)).is_none() {
+        return None;
+    }
+    Some(Wrapper { real, lib_dirs })
+}
+
+// single quotes keep everything as it is, except for single quotes
+fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+// bash for exec -a, tools that dispatch on their own name must not see the real binary's path
+fn wrapper_script(real: &Path, lib_dirs: &[PathBuf]) -> String {
+    format!(
+        "#!/bin/bash\n\
+         # written by cargo-rebuild-check, rebuilding the crate replaces it\n\
+         LD_LIBRARY_PATH={}\"${{LD_LIBRARY_PATH:+:$LD_LIBRARY_PATH}}\"\n\
+         export LD_LIBRARY_PATH\n\
+         exec -a \"$0\" {} \"$@\"\n",
+        shell_quote(&join_dirs(lib_dirs)),
+        shell_quote(&real.display().to_string())
+    )
+}
+
+fn wrap_binary(relink: &Relink, bin_dir: &Path) -> io::Result<()> {
+    let real = real_binary(bin_dir, &relink.binary);
+    fs::create_dir_all(real.parent().unwrap())?;
+    fs::rename(&relink.path, &real)?;
+    let written = fs::write(&relink.path, wrapper_script(&real, &relink.lib_dirs))
+        .and_then(|_| fs::set_permissions(&relink.path, fs::Permissions::from_mode(0o755)));
+    if written.is_err() {
+        fs::rename(&real, &relink.path)?;
+    }
+    written
+}
+
+fn unwrap_binary(relink: &Relink, bin_dir: &Path) {
+    if fs::rename(real_binary(bin_dir, &relink.binary), &relink.path).is_err() {
+        eprintln!(
+            "    Could not move '{}' back to '{}'",
+            real_binary(bin_dir, &relink.binary).display(),
+            relink.path.display()
+        );
+    }
+}
+
+// wrap the binaries of a crate that miss libraries an installed toolchain still has,
+// returns false if that does not fix the crate, it is left as it was then
+fn wrap_crate(
+    package: &CrateInfo,
+    install_root: &Path,
+    rustc_lib_path: &str,
+    toolchains: &[PathBuf],
+) -> bool {
+    let bin_dir = install_root.join("bin");
+    let relinks = match plan_relinks(package, &bin_dir, rustc_lib_path, toolchains) {
+        Some(relinks) => relinks,
+        None => return false,
+    };
+    println!("  Wrapping {}", package.name);
+
+    let old_wrappers = read_wrappers(&bin_dir);
+    let mut wrappers = old_wrappers.clone();
+    let mut wrapped = Vec::new();
+    let mut failure = None;
+    for relink in &relinks {
+        if let Err(error) = wrap_binary(relink, &bin_dir) {
+            failure = Some(format!(
+                "could not wrap binary '{}': {}",
+                relink.binary, error
+            ));
+            break;
+        }
+        println!(
+            "    Binary '{}' now runs with LD_LIBRARY_PATH={}",
+            relink.binary,
+            join_dirs(&relink.lib_dirs)
+        );
+        wrappers.retain(|(binary, _)| *binary != relink.binary);
+        wrappers.push((relink.binary.clone(), relink.lib_dirs.clone()));
+        wrapped.push(relink);
+    }
+    if failure.is_none() {
+        if let Err(error) = write_wrappers(&bin_dir, &wrappers) {
+            failure = Some(format!("could not record the wrappers: {}", error));
+        }
+    }
+    // the check finds the wrappers and looks at the real binaries
+    if failure.is_none()
+        && check_crate(
+            package,
+            &bin_dir,
+            rustc_lib_path,
+            None,
+            &CheckOptions::default(),
+        )
+        .broken
+    {
+        failure = Some(String::from("still broken with the wrappers"));
+    }
+
+    match failure {
+        Some(failure) => {
+            println!("    Wrapping {} failed, {}", package.name, failure);
+            for relink in wrapped {
+                unwrap_binary(relink, &bin_dir);
+            }
+            let _ = write_wrappers(&bin_dir, &old_wrappers);
+            false
+        }
+        None => true,
+    }
+}
+
+// wrap what can be wrapped, returns the crates that still need a rebuild
+pub(crate) fn wrap_crates<'a>(
+    broken_pkgs: &[&'a CrateInfo],
+    install_root: &Path,
+    rustc_lib_path: &str,
+) -> Vec<&'a CrateInfo> {
+    let toolchains = rustup_toolchains();
+    broken_pkgs
+        .iter()
+        .filter(|package| !wrap_crate(package, install_root, rustc_lib_path, &toolchains))
+        .cloned()
+        .collect()
+}
+
+// after a successful rebuild the binaries are real ones again, the old ones can go
+pub(crate) fn remove_wrappers(package: &CrateInfo, bin_dir: &Path) {
+    let wrappers = read_wrappers(bin_dir);
+    let (replaced, kept): (Vec<_>, Vec<_>) = wrappers.into_iter().partition(|(binary, _)| {
+        package.binaries.contains(binary) && read_shebang(&bin_dir.join(binary)).is_none()
+    });
+    if replaced.is_empty() {
+        return;
+    }
+    for (binary, _) in &replaced {
+        let _ = fs::remove_file(real_binary(bin_dir, binary));
+        println!("    Removed the wrapper of binary '{}'", binary);
+    }
+    let _ = write_wrappers(bin_dir, &kept);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use std::process::Command;
+
+    #[test]
+    fn wrap_and_unwrap() {
+        let cargo_home = FakeCargoHome::new("wrapper")
+            .broken_crate("pinned", "0.1.0", "libstd-0123456789abcdef.so")
+            .toolchain_library("old-x86_64-unknown-linux-gnu", "libstd-0123456789abcdef.so")
+            .working_crate("fine", "1.0.0")
+            .name_printing_binary("show-name")
+            .with_rustup_env()
+            .build();
+        let lib_dir = cargo_home.toolchain_lib_dir("old-x86_64-unknown-linux-gnu");
+
+        let output = cargo_home.run(&["rebuild", "--fix", "wrapper", "--full"]);
+        assert!(output.status.success());
+        assert!(output.stdout.ends_with(&format!(
+            "\n  Crates needing rebuild: pinned
+  Wrapping pinned
+    Binary 'pinned' now runs with LD_LIBRARY_PATH={}\n",
+            lib_dir.display()
+        )));
+        let bin_dir = cargo_home.bin_dir();
+        assert!(read_shebang(&bin_dir.join("pinned")).is_some());
+        assert!(Command::new(bin_dir.join("pinned"))
+            .status()
+            .unwrap()
+            .success());
+
+        let output = cargo_home.run(&["check", "--full"]);
+        assert!(output.status.success());
+        let stdout = &output.stdout;
+        assert!(stdout.contains(&format!(
+            "  Checking crate pinned 0.1.0
+    Binary 'pinned' is wrapped, it runs with LD_LIBRARY_PATH={}\n",
+            lib_dir.display()
+        )));
+        assert!(stdout.ends_with("\n  Everything looks good! :)\n"));
+
+        // the real binary sees the name it was called by
+        let real = real_binary(&bin_dir, "pinned");
+        let installed = fs::read(&real).unwrap();
+        fs::copy(bin_dir.join("show-name"), &real).unwrap();
+        let output = Command::new(bin_dir.join("pinned")).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n", bin_dir.join("pinned").display())
+        );
+        fs::write(&real, installed).unwrap();
+
+        // what a rebuild does to the wrapper
+        fs::copy(&real, bin_dir.join("pinned")).unwrap();
+        let package = decode_line(
+            "\"pinned 0.1.0 (registry+https://github.com/rust-lang/crates.io-index)\" = [\"pinned\"]",
+        );
+        remove_wrappers(&package, &bin_dir);
+        assert!(!real.exists());
+        assert!(read_wrappers(&bin_dir).is_empty());
+    }
+}